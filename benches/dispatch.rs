@@ -0,0 +1,122 @@
+//! Throughput of the mock server under parallel clients, run with `cargo bench`.
+//! The server has many mocks and the one that answers is the last one tried,
+//! so every request walks the whole list while the clients hammer it at once.
+//!
+//! The http numbers include the client and the network stack, so it also measures just
+//! taking the mock list for a request: the way the server does it, a snapshot under a
+//! read lock, against the way it used to, cloning the list under a mutex.
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use replay_mocker::{
+    mocks::ReplayMock,
+    models::{DynamicBody, Method, Replay, Request},
+    MockServer,
+};
+use serde_json::json;
+
+const MOCKS: usize = 50;
+const RUN_FOR: Duration = Duration::from_secs(3);
+
+fn replay(path: String) -> Replay {
+    Replay {
+        when: Request {
+            path,
+            queries: None,
+            method: Method::Get,
+            headers: vec![],
+            body: None,
+        },
+        then: DynamicBody::Json(json!({"ok": true})),
+        version: None,
+    }
+}
+
+async fn run(mock: &MockServer, clients: usize) -> f64 {
+    let url = mock.url(&format!("mock/{}", MOCKS - 1));
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+    let workers = (0..clients).map(|_| {
+        let (client, url) = (client.clone(), url.clone());
+        tokio::spawn(async move {
+            let mut requests = 0u64;
+            while started.elapsed() < RUN_FOR {
+                let res = client.get(&url).send().await.expect("Valid get");
+                assert!(res.status().is_success());
+                res.bytes().await.expect("Body");
+                requests += 1;
+            }
+            requests
+        })
+    });
+    let mut requests = 0;
+    for worker in workers.collect::<Vec<_>>() {
+        requests += worker.await.expect("Client task");
+    }
+    requests as f64 / started.elapsed().as_secs_f64()
+}
+
+/// How many times a second the threads together take the mock list and walk it
+fn take_lists(threads: usize, take: impl Fn() -> usize + Sync) -> f64 {
+    let started = Instant::now();
+    let taken: u64 = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut taken = 0u64;
+                    while started.elapsed() < RUN_FOR {
+                        assert_eq!(take(), MOCKS);
+                        taken += 1;
+                    }
+                    taken
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().map(|x| x.join().unwrap()).sum()
+    });
+    taken as f64 / started.elapsed().as_secs_f64()
+}
+
+fn compare_dispatch() {
+    type Mock = Arc<dyn Fn() -> bool + Send + Sync>;
+    let list = || {
+        (0..MOCKS)
+            .map(|_| Arc::new(|| true) as Mock)
+            .collect::<Vec<_>>()
+    };
+    let cloned = Mutex::new(list());
+    let snapshot = RwLock::new(Arc::new(list()));
+    for threads in [1, 4, 16, 64] {
+        let old = take_lists(threads, || {
+            let mocks = cloned.lock().unwrap().clone();
+            mocks.iter().filter(|mock| mock()).count()
+        });
+        let new = take_lists(threads, || {
+            let mocks = snapshot.read().unwrap().clone();
+            mocks.iter().filter(|mock| mock()).count()
+        });
+        println!(
+            "{:>3} threads: {:>12.0} lists/s cloned, {:>12.0} lists/s snapshot, {:.1}x",
+            threads,
+            old,
+            new,
+            new / old
+        );
+    }
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    compare_dispatch();
+    let mock = MockServer::new();
+    for index in 0..MOCKS {
+        mock.add_mock(ReplayMock::new(vec![replay(format!("/mock/{}", index))]));
+    }
+    for clients in [1, 4, 16, 64] {
+        let per_second = run(&mock, clients).await;
+        println!("{:>3} clients: {:>10.0} requests/s", clients, per_second);
+    }
+}
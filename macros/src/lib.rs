@@ -0,0 +1,53 @@
+#![deny(missing_docs)]
+//! ### Purpose
+//! Macros for replay-mocker, so cassettes can be part of the test binary.
+use std::path::PathBuf;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use replay_mocker_models::Replay;
+use syn::{parse_macro_input, LitStr};
+
+/// Embed a replay cassette in the binary and make a `ReplayMock` of it, like
+/// `embed_replays!("tests/fixtures/facts.json")`. The path is from the root of the crate
+/// using the macro, so it doesn't matter where the tests run from. The cassette is
+/// checked against the `Replay` format while compiling, so a broken cassette fails the build.
+///
+/// ```
+/// let mock = replay_mocker_macros::embed_replays!("tests/fixtures/facts.json");
+/// ```
+///
+/// A cassette with a method that isn't one, like `Fetch`, doesn't compile:
+///
+/// ```compile_fail
+/// let mock = replay_mocker_macros::embed_replays!("tests/fixtures/broken.json");
+/// ```
+#[proc_macro]
+pub fn embed_replays(input: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(input as LitStr);
+    let root = std::env::var("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let file = root.join(path.value());
+    let contents = match std::fs::read_to_string(&file) {
+        Ok(contents) => contents,
+        Err(error) => {
+            let message = format!("can't read cassette {}: {}", file.display(), error);
+            return syn::Error::new(path.span(), message)
+                .to_compile_error()
+                .into();
+        }
+    };
+    if let Err(error) = serde_json::from_str::<Vec<Replay>>(&contents) {
+        let message = format!("invalid cassette {}: {}", file.display(), error);
+        return syn::Error::new(path.span(), message)
+            .to_compile_error()
+            .into();
+    }
+    let file = file.to_string_lossy().to_string();
+    // Including the file again lets cargo rebuild when the cassette changes
+    quote!(::replay_mocker::mocks::ReplayMock::from_json(
+        include_str!(#file)
+    ))
+    .into()
+}
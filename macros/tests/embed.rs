@@ -0,0 +1,15 @@
+use replay_mocker::MockServer;
+use replay_mocker_macros::embed_replays;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn embedded_replays_test() {
+    let mock = MockServer::new().with_mock(embed_replays!("tests/fixtures/facts.json"));
+    let body: Value = reqwest::get(mock.url("facts"))
+        .await
+        .expect("Valid get")
+        .json()
+        .await
+        .expect("Serde");
+    assert_eq!(body, json!({"fact": "cats sleep a lot"}));
+}
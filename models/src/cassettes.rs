@@ -0,0 +1,147 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobMatcher};
+
+use super::{redaction, Replay, Request};
+
+/// Replays merged from many cassettes, remembering which cassette each came from.
+/// The cassettes added first take precedence: their replays are tried first.
+#[derive(Debug, Clone, Default)]
+pub struct Cassettes {
+    replays: Vec<(String, Replay)>,
+}
+
+/// A `when` that shadows later replays across the cassettes, the same request or one
+/// it matches anyway, like a subset of the body or a redacted value
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayDuplicate {
+    /// The request of the replay that wins
+    pub when: Request,
+    /// The cassettes of the winning replay and the ones it shadows, the first one wins
+    pub sources: Vec<String>,
+    /// If the replays answer differently, not just the same answer twice
+    pub conflicting: bool,
+}
+
+impl Cassettes {
+    /// No cassettes yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add replays under a name, for reporting duplicates
+    pub fn with_replays(mut self, source: &str, replays: Vec<Replay>) -> Self {
+        self.replays.extend(
+            replays
+                .into_iter()
+                .map(|replay| (source.to_string(), replay)),
+        );
+        self
+    }
+    /// Add the replays of a json file, panics when it can't be read
+    pub fn with_file(self, path: &str) -> Self {
+        self.try_with_file(Path::new(path))
+            .expect("replay from file")
+    }
+    /// Add the cassettes in a directory and the ones below it, when their path in the
+    /// directory matches the glob, like `**/*.json` or `users/*.json`. They are added in
+    /// path order, symlinked directories are not followed. Panics on an invalid glob or
+    /// a cassette that can't be read.
+    pub fn with_dir(self, dir: &str, glob: &str) -> Self {
+        let matcher = Glob::new(glob).expect("valid glob").compile_matcher();
+        self.try_with_path(Path::new(dir), &matcher)
+            .expect("replay from directory")
+    }
+    /// Add a cassette file, or the cassettes matching the glob in a directory tree
+    #[doc(hidden)]
+    pub fn try_with_path(self, path: &Path, matcher: &GlobMatcher) -> io::Result<Self> {
+        cassette_files(path, matcher)?
+            .iter()
+            .try_fold(self, |cassettes, file| cassettes.try_with_file(file))
+    }
+    fn try_with_file(self, path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let replays = serde_json::from_slice(&bytes)
+            .map_err(|error| io::Error::other(format!("{}: {}", path.display(), error)))?;
+        Ok(self.with_replays(&path.to_string_lossy(), replays))
+    }
+    /// The merged replays, in the order they are tried
+    pub fn replays(&self) -> Vec<Replay> {
+        self.replays
+            .iter()
+            .map(|(_, replay)| replay.clone())
+            .collect()
+    }
+    /// The replays that can never match, because a replay before them matches their
+    /// `when` too. They are dead weight, or a mistake when they answer differently.
+    pub fn duplicates(&self) -> Vec<ReplayDuplicate> {
+        let mut shadowed = vec![false; self.replays.len()];
+        let mut duplicates = vec![];
+        for (index, (source, replay)) in self.replays.iter().enumerate() {
+            if shadowed[index] {
+                continue;
+            }
+            let mut duplicate = ReplayDuplicate {
+                when: replay.when.clone(),
+                sources: vec![source.clone()],
+                conflicting: false,
+            };
+            for (later, (later_source, later_replay)) in
+                self.replays.iter().enumerate().skip(index + 1)
+            {
+                if shadowed[later] || !shadows(replay, later_replay) {
+                    continue;
+                }
+                shadowed[later] = true;
+                duplicate.sources.push(later_source.clone());
+                duplicate.conflicting |= later_replay.then != replay.then;
+            }
+            if duplicate.sources.len() > 1 {
+                duplicates.push(duplicate);
+            }
+        }
+        duplicates.sort_by(|a, b| a.when.path.cmp(&b.when.path));
+        duplicates
+    }
+}
+
+/// If the replay answers every request the later one would
+fn shadows(replay: &Replay, later: &Replay) -> bool {
+    // Comparing the routes first is cheap, and rules out most pairs
+    replay.when.method == later.when.method
+        && redaction::path_matches(&replay.when.path, &later.when.path)
+        && replay.matches_request(&later.when)
+}
+
+/// The cassette file, or the files in the directory tree matching the glob in path order
+#[doc(hidden)]
+pub fn cassette_files(path: &Path, matcher: &GlobMatcher) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    walk(path, &mut files)?;
+    files.retain(|file| {
+        file.strip_prefix(path)
+            .is_ok_and(|relative| matcher.is_match(relative))
+    });
+    files.sort();
+    Ok(files)
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // The entry's own type, so a symlink to a directory above can't loop forever
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&path, files)?;
+        } else if !(file_type.is_symlink() && path.is_dir()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use assert_json_diff::{CompareMode, Config};
+use graphql_parser::query::{parse_query, Definition, OperationDefinition};
+use serde_json::Value;
+
+use super::{redaction::mask_redacted, DynamicBody, Request};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A GraphQL request, read from the json body of a request, with the query normalized
+/// so formatting and comments don't change how it matches.
+pub struct GraphQlRequest {
+    /// The operation name, from `operationName` or else the first named operation
+    pub operation_name: Option<String>,
+    /// The query, printed back from the parsed document
+    pub query: String,
+    /// The variables, an empty object when missing
+    pub variables: Value,
+}
+
+impl GraphQlRequest {
+    /// Read a GraphQL request from a json body like `{"query": "...", "variables": {...}}`.
+    /// The query has to parse, so other json bodies with a `query` field aren't taken for one.
+    pub fn from_json(body: &Value) -> Option<Self> {
+        let query = body.get("query")?.as_str()?;
+        parse_query::<String>(query).ok()?;
+        Self::from_json_lenient(body)
+    }
+
+    /// Like `from_json`, also taking queries that don't parse, for endpoints known to be GraphQL.
+    /// Those are compared with their whitespace collapsed.
+    #[doc(hidden)]
+    pub fn from_json_lenient(body: &Value) -> Option<Self> {
+        let query = body.get("query")?.as_str()?;
+        let document = parse_query::<String>(query).ok();
+        let operation_name = body
+            .get("operationName")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .or_else(|| {
+                document
+                    .as_ref()?
+                    .definitions
+                    .iter()
+                    .find_map(|definition| match definition {
+                        Definition::Operation(OperationDefinition::Query(x)) => x.name.clone(),
+                        Definition::Operation(OperationDefinition::Mutation(x)) => x.name.clone(),
+                        Definition::Operation(OperationDefinition::Subscription(x)) => {
+                            x.name.clone()
+                        }
+                        _ => None,
+                    })
+            });
+        let query = match &document {
+            Some(document) => document.to_string(),
+            None => query.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        let variables = match body.get("variables") {
+            Some(Value::Null) | None => Value::Object(Default::default()),
+            Some(variables) => variables.clone(),
+        };
+        Some(Self {
+            operation_name,
+            query,
+            variables,
+        })
+    }
+
+    /// We want to know when the expected GraphQL request matches the one coming in.
+    /// The variables use fuzzy matching, like the bodies of other requests.
+    pub fn matches(&self, request: &GraphQlRequest) -> bool {
+        self.operation_name == request.operation_name
+            && self.query == request.query
+            && assert_json_diff::assert_json_matches_no_panic(
+                &mask_redacted(&self.variables, &request.variables),
+                &self.variables,
+                Config::new(CompareMode::Inclusive),
+            )
+            .is_ok()
+    }
+}
+
+impl Request {
+    /// The GraphQL request in the body, if the body is one
+    pub fn graphql(&self) -> Option<GraphQlRequest> {
+        match &self.body {
+            Some(DynamicBody::Json(body)) => GraphQlRequest::from_json(body),
+            _ => None,
+        }
+    }
+
+    /// The body without the GraphQL fields, so anything else sent with them still matches
+    /// like any other body
+    #[doc(hidden)]
+    pub fn without_graphql(&self) -> Option<DynamicBody> {
+        match &self.body {
+            Some(DynamicBody::Json(Value::Object(body))) => {
+                let mut body = body.clone();
+                for field in ["query", "operationName", "variables"] {
+                    body.remove(field);
+                }
+                Some(DynamicBody::Json(Value::Object(body)))
+            }
+            body => body.clone(),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// The status a gRPC call ends with, sent in the trailers
+pub struct GrpcStatus {
+    /// The `grpc-status` code, 0 is OK
+    #[serde(default)]
+    pub code: i32,
+    /// The `grpc-message`, usually only set on errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Any other trailing metadata
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trailers: BTreeMap<String, String>,
+}
+
+impl GrpcStatus {
+    /// The OK status
+    pub fn ok() -> Self {
+        Self {
+            code: 0,
+            message: None,
+            trailers: Default::default(),
+        }
+    }
+    /// An error status, like 5 for NOT_FOUND
+    pub fn error(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: Some(message.into()),
+            trailers: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A gRPC response, with the messages as their json representation.
+/// A gRPC mock turns these into protobuf using the descriptors.
+pub struct GrpcResponse {
+    /// The messages sent back, more than one for server streaming
+    #[serde(default)]
+    pub messages: Vec<Value>,
+    /// How the call ends
+    #[serde(flatten)]
+    pub status: GrpcStatus,
+}
+
+impl GrpcResponse {
+    /// A successful response with the messages
+    pub fn ok(messages: Vec<Value>) -> Self {
+        Self {
+            messages,
+            status: GrpcStatus::ok(),
+        }
+    }
+    /// A failed response without any messages
+    pub fn error(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            messages: vec![],
+            status: GrpcStatus::error(code, message),
+        }
+    }
+    /// Add a trailer to send with the status
+    pub fn with_trailer(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.status.trailers.insert(key.into(), value.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A gRPC response ready for the wire, with the messages already encoded
+pub struct GrpcFrames {
+    /// The encoded messages, without the length prefix
+    pub messages: Vec<Vec<u8>>,
+    /// How the call ends
+    #[serde(flatten)]
+    pub status: GrpcStatus,
+}
+
+/// Add the gRPC length prefix to a message
+pub fn encode_grpc_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Split a gRPC body into its messages, `None` when the body is cut short or compressed
+pub fn decode_grpc_frames(mut body: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut messages = vec![];
+    while !body.is_empty() {
+        if body.len() < 5 || body[0] != 0 {
+            return None;
+        }
+        let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let message = body.get(5..5 + length)?;
+        messages.push(message.to_vec());
+        body = &body[5 + length..];
+    }
+    Some(messages)
+}
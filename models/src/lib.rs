@@ -0,0 +1,408 @@
+#![deny(missing_docs)]
+//! ### Purpose
+//! The models of replay-mocker: the replays that are recorded and replayed, and the
+//! requests and bodies the mocks work with. They are apart from the server, so tools
+//! reading cassettes, like the macros, don't need to build the server.
+use std::path::PathBuf;
+
+use assert_json_diff::{CompareMode, Config};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+mod cassettes;
+mod graphql;
+mod grpc;
+mod recorder;
+mod redaction;
+mod replay_index;
+mod sse;
+mod stream;
+mod websocket;
+
+pub use cassettes::*;
+pub use graphql::*;
+pub use grpc::*;
+pub use recorder::*;
+pub use redaction::{Redaction, REDACTED};
+#[doc(hidden)]
+pub use replay_index::ReplayIndex;
+pub use sse::SseEvent;
+#[doc(hidden)]
+pub use sse::SseParser;
+pub use stream::*;
+pub use websocket::*;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// These are the allowed methods as per the standard rest
+pub enum Method {
+    ///REST Post
+    Post,
+    ///REST Put
+    Put,
+    ///REST Get
+    Get,
+    ///REST Delete
+    Delete,
+    ///REST Trace
+    Trace,
+    ///REST HEAD
+    Head,
+    ///REST Connect
+    Connect,
+    ///REST patch
+    Patch,
+    ///REST OPTIONS
+    Options,
+    /// Any other verb, like WebDAV's `PROPFIND` or a cache `PURGE`
+    Other(String),
+}
+
+impl Method {
+    /// Return the method as a string, for parsing reasons
+    pub fn as_method_string(&self) -> String {
+        match self {
+            Method::Post => "POST".to_string(),
+            Method::Put => "PUT".to_string(),
+            Method::Get => "GET".to_string(),
+            Method::Delete => "DELETE".to_string(),
+            Method::Trace => "TRACE".to_string(),
+            Method::Head => "HEAD".to_string(),
+            Method::Connect => "CONNECT".to_string(),
+            Method::Patch => "PATCH".to_string(),
+            Method::Options => "OPTIONS".to_string(),
+            Method::Other(method) => method.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Request bodies could be a json or bytes
+pub enum DynamicBody {
+    /// This is the text bodies
+    Text(String),
+    /// This is the raw bodies
+    Bytes(Vec<u8>),
+    /// This is when we are doing json
+    Json(Value),
+    /// This is a chunked body, each chunk sent after its delay
+    Chunks(Vec<Chunk>),
+    /// This is a file on disk, streamed when we respond
+    File(PathBuf),
+    /// This is a server sent event stream, each event sent after its delay
+    Events(Vec<SseEvent>),
+    /// This is a live stream, it can't be saved in a replay
+    #[serde(skip)]
+    Stream(BodyStream),
+    /// This is a scripted websocket conversation, for websocket upgrades
+    WebSocket(Vec<WsStep>),
+    /// This is a live websocket proxy, it can't be saved in a replay
+    #[serde(skip)]
+    WebSocketProxy(Box<WsProxy>),
+    /// This is a gRPC response, with json messages that a gRPC mock encodes
+    Grpc(GrpcResponse),
+    /// This is a gRPC response with the messages already encoded
+    GrpcFrames(GrpcFrames),
+    /// This is a body sent with response headers, like the ones a gateway records
+    WithHeaders {
+        /// The headers, in order, a name can show up more than once
+        headers: Vec<(String, String)>,
+        /// The body sent with them
+        body: Box<DynamicBody>,
+    },
+    /// This is a body sent with a status code other than 200, like a 503
+    WithStatus {
+        /// The http status code
+        status: u16,
+        /// The body sent with it
+        body: Box<DynamicBody>,
+    },
+}
+impl DynamicBody {
+    /// Send the body with these response headers
+    pub fn with_headers(self, headers: Vec<(String, String)>) -> Self {
+        DynamicBody::WithHeaders {
+            headers,
+            body: Box::new(self),
+        }
+    }
+    /// Send the body with this status code
+    pub fn with_status(self, status: u16) -> Self {
+        DynamicBody::WithStatus {
+            status,
+            body: Box::new(self),
+        }
+    }
+    /// The body without the response headers or status around it
+    pub fn body(&self) -> &DynamicBody {
+        match self {
+            DynamicBody::WithHeaders { body, .. } | DynamicBody::WithStatus { body, .. } => {
+                body.body()
+            }
+            body => body,
+        }
+    }
+    /// The body without the response headers or status around it, to change it
+    pub fn body_mut(&mut self) -> &mut DynamicBody {
+        match self {
+            DynamicBody::WithHeaders { body, .. } | DynamicBody::WithStatus { body, .. } => {
+                body.body_mut()
+            }
+            body => body,
+        }
+    }
+    /// Change the speed that a timed body plays at, a speed of 2.0 plays twice as fast.
+    /// Bodies without timing are returned as is.
+    pub fn with_speed(self, speed: f64) -> Self {
+        let scale = |delay_ms: u64| {
+            if speed > 0.0 {
+                (delay_ms as f64 / speed) as u64
+            } else {
+                0
+            }
+        };
+        match self {
+            DynamicBody::Chunks(chunks) => DynamicBody::Chunks(
+                chunks
+                    .into_iter()
+                    .map(|chunk| Chunk {
+                        delay_ms: scale(chunk.delay_ms),
+                        ..chunk
+                    })
+                    .collect(),
+            ),
+            DynamicBody::Events(events) => DynamicBody::Events(
+                events
+                    .into_iter()
+                    .map(|event| SseEvent {
+                        delay_ms: scale(event.delay_ms),
+                        ..event
+                    })
+                    .collect(),
+            ),
+            DynamicBody::WebSocket(steps) => DynamicBody::WebSocket(
+                steps
+                    .into_iter()
+                    .map(|step| match step {
+                        WsStep::Send { delay_ms, message } => WsStep::Send {
+                            delay_ms: scale(delay_ms),
+                            message,
+                        },
+                        step => step,
+                    })
+                    .collect(),
+            ),
+            DynamicBody::WithHeaders { headers, body } => DynamicBody::WithHeaders {
+                headers,
+                body: Box::new(body.with_speed(speed)),
+            },
+            DynamicBody::WithStatus { status, body } => DynamicBody::WithStatus {
+                status,
+                body: Box::new(body.with_speed(speed)),
+            },
+            body => body,
+        }
+    }
+}
+impl From<Value> for DynamicBody {
+    fn from(value: Value) -> Self {
+        DynamicBody::Json(value)
+    }
+}
+impl From<Vec<u8>> for DynamicBody {
+    fn from(value: Vec<u8>) -> Self {
+        DynamicBody::Bytes(value)
+    }
+}
+impl From<Vec<Chunk>> for DynamicBody {
+    fn from(value: Vec<Chunk>) -> Self {
+        DynamicBody::Chunks(value)
+    }
+}
+impl From<Vec<SseEvent>> for DynamicBody {
+    fn from(value: Vec<SseEvent>) -> Self {
+        DynamicBody::Events(value)
+    }
+}
+impl From<Vec<WsStep>> for DynamicBody {
+    fn from(value: Vec<WsStep>) -> Self {
+        DynamicBody::WebSocket(value)
+    }
+}
+impl From<GrpcResponse> for DynamicBody {
+    fn from(value: GrpcResponse) -> Self {
+        DynamicBody::Grpc(value)
+    }
+}
+impl From<BodyStream> for DynamicBody {
+    fn from(value: BodyStream) -> Self {
+        DynamicBody::Stream(value)
+    }
+}
+impl From<&str> for DynamicBody {
+    fn from(value: &str) -> Self {
+        DynamicBody::Text(value.to_string())
+    }
+}
+impl From<String> for DynamicBody {
+    fn from(value: String) -> Self {
+        DynamicBody::Text(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A request is the simplified abstracted structure of a json rest
+pub struct Request {
+    /// Path in the request
+    pub path: String,
+    /// queries in the request
+    pub queries: Option<String>,
+    /// Method of the request
+    pub method: Method,
+    /// Headers of the request, they don't change how a replay matches and are
+    /// recorded by a gateway unless asked not to, old replays without them still load
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    /// Body of the request, if there was one
+    pub body: Option<DynamicBody>,
+}
+
+impl Request {
+    /// Read a streamed body whole, for the mocks that match on it.
+    /// A stream that was already taken leaves no body.
+    #[doc(hidden)]
+    pub async fn buffer_body(&mut self) {
+        let stream = match &self.body {
+            Some(DynamicBody::Stream(body_stream)) => body_stream.take(),
+            _ => return,
+        };
+        let mut bytes = vec![];
+        if let Some(stream) = stream {
+            let mut stream = Box::pin(stream);
+            while let Some(Ok(chunk)) = stream.next().await {
+                bytes.extend_from_slice(&chunk);
+            }
+        }
+        self.body = (!bytes.is_empty()).then_some(DynamicBody::Bytes(bytes));
+    }
+    /// If the request asks to upgrade to a websocket, only those are answered by websocket mocks
+    #[doc(hidden)]
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("upgrade") && value.to_lowercase().contains("websocket")
+        })
+    }
+    /// We want to know when a Replay matches the request coming in
+    pub fn matches_body(&self, body: &Value) -> bool {
+        match &self.body {
+            Some(DynamicBody::Json(personal_body)) => {
+                assert_json_diff::assert_json_matches_no_panic(
+                    &personal_body,
+                    &body,
+                    Config::new(CompareMode::Inclusive),
+                )
+                .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+/// The http protocol version a response was served with
+pub enum HttpVersion {
+    /// HTTP/1.0
+    #[serde(rename = "HTTP/1.0")]
+    Http10,
+    /// HTTP/1.1
+    #[serde(rename = "HTTP/1.1")]
+    Http11,
+    /// HTTP/2
+    #[serde(rename = "HTTP/2")]
+    Http2,
+    /// HTTP/3
+    #[serde(rename = "HTTP/3")]
+    Http3,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Replay is the structure to tie a pattern of if you see this then do that
+/// This uses fuzzy typing on the request body.
+pub struct Replay {
+    /// When a request condition happens
+    pub when: Request,
+    /// Return this value
+    pub then: DynamicBody,
+    /// The protocol version the upstream answered with, when recorded by a gateway
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<HttpVersion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A request the mock server got, and if any mock answered it
+pub struct JournalEntry {
+    /// The request as it came in
+    pub request: Request,
+    /// If a mock answered the request
+    pub matched: bool,
+    /// The id of the mock that answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mock_id: Option<u64>,
+    /// The name of the mock that answered, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mock_name: Option<String>,
+}
+
+impl Replay {
+    /// We want to know when a Replay matches the request coming in
+    /// GraphQL bodies match on the operation, the normalized query and the variables.
+    /// Redacted values in the replay match any value, in the path they match one segment.
+    pub fn matches_request(&self, request: &Request) -> bool {
+        if !redaction::path_matches(&self.when.path, &request.path)
+            || self.when.method != request.method
+            || !redaction::queries_match(&self.when.queries, &request.queries)
+        {
+            return false;
+        }
+        if let (Some(expected), Some(actual)) = (self.when.graphql(), request.graphql()) {
+            return expected.matches(&actual)
+                && bodies_match(&self.when.without_graphql(), &request.without_graphql());
+        }
+        if let (Some(DynamicBody::Bytes(expected)), Some(DynamicBody::Bytes(actual))) =
+            (&self.when.body, &request.body)
+        {
+            if let (Ok(expected), Ok(actual)) =
+                (std::str::from_utf8(expected), std::str::from_utf8(actual))
+            {
+                if expected.contains(REDACTED) {
+                    return redaction::text_matches(expected, actual);
+                }
+            }
+        }
+        bodies_match(&self.when.body, &request.body)
+    }
+}
+
+/// The fuzzy body matching, the actual body has to include the expected one
+fn bodies_match(expected: &Option<DynamicBody>, actual: &Option<DynamicBody>) -> bool {
+    // Requests with a query used to be recorded with an empty body instead of none
+    let expected = match expected {
+        Some(DynamicBody::Bytes(bytes)) if bytes.is_empty() => &None,
+        body => body,
+    };
+    if let (Some(DynamicBody::Bytes(expected)), Some(DynamicBody::Bytes(actual))) =
+        (expected, actual)
+    {
+        return redaction::bytes_match(expected, actual);
+    }
+    let (expected, actual) = match (serde_json::to_value(expected), serde_json::to_value(actual)) {
+        (Ok(expected), Ok(actual)) => (expected, actual),
+        _ => return false,
+    };
+    assert_json_diff::assert_json_matches_no_panic(
+        &redaction::mask_redacted(&expected, &actual),
+        &expected,
+        Config::new(CompareMode::Inclusive),
+    )
+    .is_ok()
+}
@@ -0,0 +1,120 @@
+use std::{
+    fs::File,
+    io::Write,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use tracing::warn;
+
+use super::{Redaction, Replay};
+
+/// What the writer of a recorder is asked to do, in the order it was asked
+enum Command {
+    Redact(Redaction),
+    Record(Box<Replay>),
+    Replays(Sender<Vec<Replay>>),
+    Flush(Sender<()>),
+}
+
+/// Recorder collects the replays captured by a proxy. If we have a file name,
+/// the replays are saved to it once the last user of the recorder is gone, so
+/// streams and sessions still in flight make it into the file. `flush` saves it sooner.
+/// Recordings are sent to a writer thread that redacts and keeps them, so concurrent
+/// requests never wait on each other to record. Secrets never reach the file.
+#[derive(Debug)]
+pub struct Recorder {
+    commands: Sender<Command>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Recorder {
+    /// Create a recorder that saves to the file when dropped
+    pub fn new(file: Option<String>) -> Self {
+        let (commands, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || write(file, received))
+            .expect("starting the recorder");
+        Self { commands }
+    }
+
+    /// Add a rule to scrub the replays recorded from now on
+    pub fn add_redaction(&self, redaction: Redaction) {
+        let _ = self.commands.send(Command::Redact(redaction));
+    }
+
+    /// Add a replay to the recording
+    pub fn record(&self, replay: Replay) {
+        let _ = self.commands.send(Command::Record(Box::new(replay)));
+    }
+
+    /// A copy of the replays recorded so far
+    pub fn replays(&self) -> Vec<Replay> {
+        let (reply, replays) = mpsc::channel();
+        self.commands
+            .send(Command::Replays(reply))
+            .expect("getting replays");
+        replays.recv().expect("getting replays")
+    }
+
+    /// Save the replays recorded so far to the file, if we have one and recorded any.
+    /// Returns once they are written.
+    pub fn flush(&self) {
+        let (reply, done) = mpsc::channel();
+        if self.commands.send(Command::Flush(reply)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// The writer of a recorder, until the recorder is gone
+fn write(file: Option<String>, commands: Receiver<Command>) {
+    let mut redactions: Vec<Redaction> = vec![];
+    let mut replays = vec![];
+    for command in commands {
+        match command {
+            Command::Redact(redaction) => redactions.push(redaction),
+            Command::Record(mut replay) => {
+                for redaction in &redactions {
+                    redaction.redact(&mut replay);
+                }
+                replays.push(*replay);
+            }
+            Command::Replays(reply) => {
+                let _ = reply.send(replays.clone());
+            }
+            Command::Flush(done) => {
+                save(file.as_deref(), &replays);
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Save the replays to the file, if we have one and recorded any
+fn save(file: Option<&str>, replays: &[Replay]) {
+    let file = match file {
+        Some(file) => file,
+        None => return,
+    };
+    if replays.is_empty() {
+        return;
+    }
+    let replays_bytes = serde_json::to_vec(replays).expect("Serializing the replays");
+    let written = File::create(file).and_then(|mut x| x.write_all(&replays_bytes));
+    if let Err(error) = written {
+        warn!("Can't write replays to {}: {}", file, error);
+    }
+}
@@ -0,0 +1,285 @@
+use regex::Regex;
+use serde_json::Value;
+
+use super::{DynamicBody, Replay, Request, WsMessage, WsStep};
+
+/// The placeholder that replaces redacted values. When a replay has it, any
+/// value in that place matches.
+pub const REDACTED: &str = "__REDACTED__";
+
+#[derive(Debug, Clone)]
+/// A rule for scrubbing secrets out of replays before they are saved. The value is
+/// replaced with [`REDACTED`], and on replay a redacted value matches any value.
+pub enum Redaction {
+    /// Replace the value of a query parameter, like `api_key`
+    QueryParam(String),
+    /// Replace the value at a json pointer, like `/user/password`, in request and response bodies
+    JsonPointer(String),
+    /// Replace every match of the regex in text bodies and header values
+    Regex(Regex),
+    /// Replace the value of a header, like `authorization` or `cookie`, in requests and responses
+    Header(String),
+}
+
+impl Redaction {
+    /// Replace the value of a query parameter
+    pub fn query_param(name: &str) -> Self {
+        Redaction::QueryParam(name.to_string())
+    }
+    /// Replace the value at a json pointer
+    pub fn json_pointer(pointer: &str) -> Self {
+        Redaction::JsonPointer(pointer.to_string())
+    }
+    /// Replace every match of the regex in text bodies, panics on an invalid regex
+    pub fn regex(regex: &str) -> Self {
+        Redaction::Regex(Regex::new(regex).expect("valid redaction regex"))
+    }
+    /// Replace the value of a header, the name is not case sensitive
+    pub fn header(name: &str) -> Self {
+        Redaction::Header(name.to_string())
+    }
+
+    fn redact_queries(&self, queries: &str) -> String {
+        match self {
+            Redaction::QueryParam(name) => queries
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, _)) if key == name => format!("{}={}", key, REDACTED),
+                    _ => pair.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("&"),
+            Redaction::Regex(regex) => regex.replace_all(queries, REDACTED).to_string(),
+            Redaction::JsonPointer(_) | Redaction::Header(_) => queries.to_string(),
+        }
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match self {
+            Redaction::JsonPointer(pointer) => {
+                if let Some(value) = value.pointer_mut(pointer) {
+                    *value = Value::String(REDACTED.to_string());
+                }
+            }
+            Redaction::Regex(regex) => redact_json_strings(regex, value),
+            Redaction::QueryParam(_) | Redaction::Header(_) => (),
+        }
+    }
+
+    fn redact_headers(&self, headers: &mut [(String, String)]) {
+        for (name, value) in headers.iter_mut() {
+            match self {
+                Redaction::Header(header) if name.eq_ignore_ascii_case(header) => {
+                    *value = REDACTED.to_string()
+                }
+                Redaction::Regex(regex) => *value = regex.replace_all(value, REDACTED).to_string(),
+                _ => (),
+            }
+        }
+    }
+
+    fn redact_body(&self, body: &mut DynamicBody) {
+        match body {
+            DynamicBody::Json(value) => self.redact_json(value),
+            DynamicBody::Text(text) => {
+                if let Redaction::Regex(regex) = self {
+                    *text = regex.replace_all(text, REDACTED).to_string();
+                }
+            }
+            DynamicBody::Bytes(bytes) => self.redact_bytes(bytes),
+            DynamicBody::Chunks(chunks) => {
+                for chunk in chunks.iter_mut() {
+                    self.redact_bytes(&mut chunk.data);
+                }
+            }
+            DynamicBody::Events(events) => {
+                if let Redaction::Regex(regex) = self {
+                    for event in events.iter_mut() {
+                        event.data = regex.replace_all(&event.data, REDACTED).to_string();
+                    }
+                }
+            }
+            DynamicBody::WebSocket(steps) => {
+                for step in steps.iter_mut() {
+                    match step {
+                        WsStep::Expect(message) | WsStep::Send { message, .. } => {
+                            self.redact_message(message)
+                        }
+                        WsStep::Close => (),
+                    }
+                }
+            }
+            DynamicBody::WithHeaders { headers, body } => {
+                self.redact_headers(headers);
+                self.redact_body(body);
+            }
+            DynamicBody::WithStatus { body, .. } => self.redact_body(body),
+            DynamicBody::Grpc(response) => {
+                for message in response.messages.iter_mut() {
+                    self.redact_json(message);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn redact_bytes(&self, bytes: &mut Vec<u8>) {
+        if let Redaction::Regex(regex) = self {
+            // Binary bodies need a bytes regex, it also handles text the same way
+            if let Ok(regex) = regex::bytes::Regex::new(regex.as_str()) {
+                *bytes = regex.replace_all(bytes, REDACTED.as_bytes()).into_owned();
+            }
+        }
+    }
+
+    fn redact_message(&self, message: &mut WsMessage) {
+        match message {
+            WsMessage::Json(value) => self.redact_json(value),
+            WsMessage::Binary(bytes) => self.redact_bytes(bytes),
+            WsMessage::Text(text) => {
+                if let Redaction::Regex(regex) = self {
+                    *text = regex.replace_all(text, REDACTED).to_string();
+                }
+            }
+        }
+    }
+
+    fn redact_request(&self, request: &mut Request) {
+        if let Some(queries) = &request.queries {
+            request.queries = Some(self.redact_queries(queries));
+        }
+        self.redact_headers(&mut request.headers);
+        if let Some(body) = &mut request.body {
+            self.redact_body(body);
+        }
+    }
+
+    /// Scrub the request and response of a replay
+    pub fn redact(&self, replay: &mut Replay) {
+        self.redact_request(&mut replay.when);
+        self.redact_body(&mut replay.then);
+    }
+}
+
+fn redact_json_strings(regex: &Regex, value: &mut Value) {
+    match value {
+        Value::String(text) => *text = regex.replace_all(text, REDACTED).to_string(),
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| redact_json_strings(regex, value)),
+        Value::Object(values) => values
+            .values_mut()
+            .for_each(|value| redact_json_strings(regex, value)),
+        _ => (),
+    }
+}
+
+/// Compare the queries, where a redacted value in the expected matches any value
+pub(crate) fn queries_match(expected: &Option<String>, actual: &Option<String>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) if expected.contains(REDACTED) => {
+            text_matches(expected, actual)
+        }
+        (expected, actual) => expected == actual,
+    }
+}
+
+/// Compare text, where each placeholder in the expected matches any text
+pub(crate) fn text_matches(expected: &str, actual: &str) -> bool {
+    wildcard_matches(expected, actual, ".*?")
+}
+
+/// Compare paths, where each placeholder in the expected matches any text in one segment
+pub(crate) fn path_matches(expected: &str, actual: &str) -> bool {
+    if expected.contains(REDACTED) {
+        wildcard_matches(expected, actual, "[^/]*?")
+    } else {
+        expected == actual
+    }
+}
+
+/// Compare bytes, where each placeholder in the expected matches any bytes
+pub(crate) fn bytes_match(expected: &[u8], actual: &[u8]) -> bool {
+    let parts = split_bytes(expected, REDACTED.as_bytes());
+    if parts.len() == 1 {
+        return expected == actual;
+    }
+    let pattern = parts
+        .iter()
+        .map(|part| {
+            part.iter()
+                .map(|byte| format!("\\x{:02x}", byte))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(".*?");
+    regex::bytes::Regex::new(&format!("^(?s-u:{})$", pattern))
+        .map(|regex| regex.is_match(actual))
+        .unwrap_or(false)
+}
+
+fn split_bytes<'a>(bytes: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut index = 0;
+    while index + separator.len() <= bytes.len() {
+        if &bytes[index..index + separator.len()] == separator {
+            parts.push(&bytes[start..index]);
+            index += separator.len();
+            start = index;
+        } else {
+            index += 1;
+        }
+    }
+    parts.push(&bytes[start..]);
+    parts
+}
+
+fn wildcard_matches(expected: &str, actual: &str, wildcard: &str) -> bool {
+    let pattern = expected
+        .split(REDACTED)
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(wildcard);
+    Regex::new(&format!("^(?s:{})$", pattern))
+        .map(|regex| regex.is_match(actual))
+        .unwrap_or(false)
+}
+
+/// A copy of the actual value, with the places the expected is redacted also redacted,
+/// so fuzzy matching of the two ignores those values
+pub(crate) fn mask_redacted(expected: &Value, actual: &Value) -> Value {
+    match (expected, actual) {
+        (Value::String(expected), _) if expected == REDACTED => Value::String(REDACTED.to_string()),
+        (Value::String(expected), Value::String(actual)) if expected.contains(REDACTED) => {
+            if text_matches(expected, actual) {
+                Value::String(expected.clone())
+            } else {
+                Value::String(actual.clone())
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => Value::Array(
+            actual
+                .iter()
+                .enumerate()
+                .map(|(index, actual)| match expected.get(index) {
+                    Some(expected) => mask_redacted(expected, actual),
+                    None => actual.clone(),
+                })
+                .collect(),
+        ),
+        (Value::Object(expected), Value::Object(actual)) => Value::Object(
+            actual
+                .iter()
+                .map(|(key, actual)| {
+                    let masked = match expected.get(key) {
+                        Some(expected) => mask_redacted(expected, actual),
+                        None => actual.clone(),
+                    };
+                    (key.clone(), masked)
+                })
+                .collect(),
+        ),
+        (_, actual) => actual.clone(),
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use super::{redaction::path_matches, DynamicBody, Method, Replay, Request, REDACTED};
+
+/// Replays indexed by method and path, so finding the replay for a request only compares
+/// bodies with the replays for that route. Replays with a placeholder in the path can
+/// match many paths, so they are kept apart and checked for every request.
+/// The first matching replay still wins, in the order the replays were given.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayIndex {
+    replays: Vec<Replay>,
+    routes: HashMap<(Method, String), Vec<usize>>,
+    wildcards: Vec<usize>,
+}
+
+impl ReplayIndex {
+    /// Index the replays, keeping their order
+    pub fn new(replays: Vec<Replay>) -> Self {
+        let mut routes: HashMap<(Method, String), Vec<usize>> = HashMap::new();
+        let mut wildcards = vec![];
+        for (index, replay) in replays.iter().enumerate() {
+            if replay.when.path.contains(REDACTED) {
+                wildcards.push(index);
+            } else {
+                routes
+                    .entry((replay.when.method.clone(), replay.when.path.clone()))
+                    .or_default()
+                    .push(index);
+            }
+        }
+        Self {
+            replays,
+            routes,
+            wildcards,
+        }
+    }
+
+    /// The replays, in the order they were given
+    pub fn replays(&self) -> &[Replay] {
+        &self.replays
+    }
+
+    /// If any replay could match the method and path, whatever the query and body
+    pub fn routes_to(&self, request: &Request) -> bool {
+        self.routes
+            .contains_key(&(request.method.clone(), request.path.clone()))
+            || self.wildcards.iter().any(|index| {
+                let when = &self.replays[*index].when;
+                when.method == request.method && path_matches(&when.path, &request.path)
+            })
+    }
+
+    /// The first replay that matches the request
+    pub fn find(&self, request: &Request) -> Option<&Replay> {
+        let route = self
+            .routes
+            .get(&(request.method.clone(), request.path.clone()))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let (mut route, mut wildcards) =
+            (route.iter().peekable(), self.wildcards.iter().peekable());
+        // Both lists are in replay order, so merging them keeps the first match first
+        loop {
+            let index = match (route.peek(), wildcards.peek()) {
+                (Some(a), Some(b)) if a < b => route.next(),
+                (Some(_), Some(_)) | (None, Some(_)) => wildcards.next(),
+                (Some(_), None) => route.next(),
+                (None, None) => return None,
+            }?;
+            let replay = &self.replays[*index];
+            // A recorded websocket session only answers websocket upgrades, like a websocket mock
+            let is_websocket = matches!(replay.then.body(), DynamicBody::WebSocket(_));
+            if replay.matches_request(request) && (!is_websocket || request.is_websocket_upgrade())
+            {
+                return Some(replay);
+            }
+        }
+    }
+}
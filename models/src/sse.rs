@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A single server sent event, sent after waiting `delay_ms`
+pub struct SseEvent {
+    /// How long to wait, in milliseconds, before sending this event
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// The `event:` field, the type of the event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// The `id:` field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The `retry:` field, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
+    /// The `data:` field, multiple lines are sent as multiple `data:` lines
+    pub data: String,
+}
+
+impl SseEvent {
+    /// Create an event with just data
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            delay_ms: 0,
+            event: None,
+            id: None,
+            retry: None,
+            data: data.into(),
+        }
+    }
+    /// Wait before sending this event
+    pub fn with_delay(mut self, delay_ms: u64) -> Self {
+        self.delay_ms = delay_ms;
+        self
+    }
+    /// Set the type of the event
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+    /// Set the id of the event
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+    /// Set the retry of the event
+    pub fn with_retry(mut self, retry: u64) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event in the wire format, including the blank line ending it
+    pub fn to_wire(&self) -> String {
+        let mut wire = String::new();
+        if let Some(id) = &self.id {
+            wire.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(event) = &self.event {
+            wire.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(retry) = self.retry {
+            wire.push_str(&format!("retry: {}\n", retry));
+        }
+        for line in self.data.split('\n') {
+            wire.push_str(&format!("data: {}\n", line));
+        }
+        wire.push('\n');
+        wire
+    }
+
+    fn from_wire(block: &str) -> Option<Self> {
+        let mut event = SseEvent::new("");
+        let mut data: Vec<&str> = vec![];
+        let mut has_field = false;
+        for line in block.lines() {
+            if line.is_empty() || line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = match line.find(':') {
+                Some(index) => {
+                    let value = &line[index + 1..];
+                    (&line[..index], value.strip_prefix(' ').unwrap_or(value))
+                }
+                None => (line, ""),
+            };
+            has_field = true;
+            match field {
+                "data" => data.push(value),
+                "event" => event.event = Some(value.to_string()),
+                "id" => event.id = Some(value.to_string()),
+                "retry" => event.retry = value.parse().ok(),
+                _ => (),
+            }
+        }
+        if !has_field {
+            return None;
+        }
+        event.data = data.join("\n");
+        Some(event)
+    }
+}
+
+/// Collects bytes of an event stream and splits them into events,
+/// as they are completed.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Add bytes from the stream, returning all the events they completed
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut normalized = Vec::with_capacity(self.buffer.len());
+        for (index, byte) in self.buffer.iter().enumerate() {
+            if *byte == b'\r' && self.buffer.get(index + 1) == Some(&b'\n') {
+                continue;
+            }
+            normalized.push(*byte);
+        }
+        self.buffer = normalized;
+        let mut events = vec![];
+        while let Some(index) = self.buffer.windows(2).position(|x| x == b"\n\n") {
+            let block: Vec<u8> = self.buffer.drain(..index + 2).collect();
+            events.extend(SseEvent::from_wire(&String::from_utf8_lossy(&block)));
+        }
+        events
+    }
+}
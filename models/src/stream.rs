@@ -0,0 +1,96 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+type BoxedStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A piece of a chunked body, so we can replay the timing of a progressive response
+pub struct Chunk {
+    /// How long to wait, in milliseconds, before sending this chunk
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// The bytes sent in this chunk
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Create a chunk that is sent after a delay
+    pub fn new(delay_ms: u64, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            delay_ms,
+            data: data.into(),
+        }
+    }
+}
+
+/// A live stream of bytes, used when the body is produced while we respond.
+/// The stream can only be consumed once, so clones share the same stream.
+/// gRPC request bodies come in as one too, so a gRPC gateway can pass them on as they arrive.
+#[derive(Clone)]
+pub struct BodyStream {
+    stream: Arc<Mutex<Option<BoxedStream>>>,
+    trailers: Arc<Mutex<Option<oneshot::Receiver<HeaderMap>>>>,
+    /// The content type to respond with, if known
+    pub content_type: Option<String>,
+}
+
+impl BodyStream {
+    /// Wrap a stream of bytes so it can be used as a body
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, io::Error>> + Send + 'static,
+    {
+        Self {
+            stream: Arc::new(Mutex::new(Some(stream.boxed()))),
+            trailers: Default::default(),
+            content_type: None,
+        }
+    }
+
+    /// Set the content type to respond with
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Send these trailers once the stream is done, like the status of a gRPC call
+    #[doc(hidden)]
+    pub fn with_trailers(self, trailers: oneshot::Receiver<HeaderMap>) -> Self {
+        *self.trailers.lock().unwrap() = Some(trailers);
+        self
+    }
+
+    /// Take the trailers out, this will only return them the first time
+    #[doc(hidden)]
+    pub fn take_trailers(&self) -> Option<oneshot::Receiver<HeaderMap>> {
+        self.trailers.lock().unwrap().take()
+    }
+
+    /// Take the stream out, this will only return it the first time
+    pub fn take(&self) -> Option<impl Stream<Item = Result<Bytes, io::Error>> + Send + 'static> {
+        self.stream.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("content_type", &self.content_type)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for BodyStream {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.stream, &other.stream)
+    }
+}
@@ -0,0 +1,132 @@
+use std::{fmt, sync::Arc};
+
+use assert_json_diff::{CompareMode, Config};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{redaction, Recorder, Request};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// A message sent over a websocket
+pub enum WsMessage {
+    /// A text message
+    Text(String),
+    /// A binary message
+    Binary(Vec<u8>),
+    /// A text message holding json, when expected this uses fuzzy matching
+    Json(Value),
+}
+
+impl WsMessage {
+    /// We want to know when a scripted message matches what the client sent
+    pub fn matches(&self, received: &WsMessage) -> bool {
+        match (self, received) {
+            (WsMessage::Json(expected), WsMessage::Text(text)) => {
+                match serde_json::from_str::<Value>(text) {
+                    Ok(actual) => assert_json_diff::assert_json_matches_no_panic(
+                        &redaction::mask_redacted(expected, &actual),
+                        expected,
+                        Config::new(CompareMode::Inclusive),
+                    )
+                    .is_ok(),
+                    Err(_) => false,
+                }
+            }
+            (WsMessage::Text(expected), WsMessage::Text(received)) => {
+                expected == received || redaction::text_matches(expected, received)
+            }
+            (WsMessage::Binary(expected), WsMessage::Binary(received)) => {
+                redaction::bytes_match(expected, received)
+            }
+            (expected, received) => expected == received,
+        }
+    }
+}
+
+impl From<&str> for WsMessage {
+    fn from(value: &str) -> Self {
+        WsMessage::Text(value.to_string())
+    }
+}
+impl From<String> for WsMessage {
+    fn from(value: String) -> Self {
+        WsMessage::Text(value)
+    }
+}
+impl From<Value> for WsMessage {
+    fn from(value: Value) -> Self {
+        WsMessage::Json(value)
+    }
+}
+impl From<Vec<u8>> for WsMessage {
+    fn from(value: Vec<u8>) -> Self {
+        WsMessage::Binary(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// One step of a scripted websocket conversation, run in order
+pub enum WsStep {
+    /// Wait for the client to send a message matching this one
+    Expect(WsMessage),
+    /// Send a message to the client after waiting `delay_ms`
+    Send {
+        /// How long to wait, in milliseconds, before sending
+        #[serde(default)]
+        delay_ms: u64,
+        /// The message to send
+        message: WsMessage,
+    },
+    /// Close the connection
+    Close,
+}
+
+impl WsStep {
+    /// Wait for the client to send a matching message
+    pub fn expect(message: impl Into<WsMessage>) -> Self {
+        WsStep::Expect(message.into())
+    }
+    /// Send a message to the client straight away
+    pub fn send(message: impl Into<WsMessage>) -> Self {
+        WsStep::Send {
+            delay_ms: 0,
+            message: message.into(),
+        }
+    }
+    /// Send a message to the client after a delay
+    pub fn send_after(delay_ms: u64, message: impl Into<WsMessage>) -> Self {
+        WsStep::Send {
+            delay_ms,
+            message: message.into(),
+        }
+    }
+}
+
+/// A live websocket proxy, used while recording a session with an upstream.
+/// When the session is done it is recorded as a scripted conversation.
+#[derive(Clone)]
+pub struct WsProxy {
+    /// The websocket uri of the upstream, like `ws://localhost:8080/chat`
+    pub upstream: String,
+    /// The request that opened the session
+    pub request: Request,
+    /// Where the session goes when it is done
+    pub recorder: Arc<Recorder>,
+}
+
+impl fmt::Debug for WsProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsProxy")
+            .field("upstream", &self.upstream)
+            .field("request", &self.request)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for WsProxy {
+    fn eq(&self, other: &Self) -> bool {
+        self.upstream == other.upstream
+            && self.request == other.request
+            && Arc::ptr_eq(&self.recorder, &other.recorder)
+    }
+}
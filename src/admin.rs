@@ -0,0 +1,130 @@
+use std::convert::Infallible;
+
+use serde::Deserialize;
+use serde_json::json;
+use warp::{body::BodyDeserializeError, http::StatusCode, reject::MethodNotAllowed, Filter};
+
+use crate::{mocks::ReplayMock, models::Replay, with_sendable, MockOptions, State};
+
+/// One replay or a whole cassette of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Replays {
+    Many(Vec<Replay>),
+    One(Box<Replay>),
+}
+
+/// The name and comma separated tags of an added mock, from the query
+#[derive(Deserialize)]
+struct AddOptions {
+    name: Option<String>,
+    tags: Option<String>,
+}
+
+/// The admin api under `/__admin/`, so tests in other processes can drive the server.
+/// - `POST /__admin/replays?name=..&tags=a,b` adds a replay, or a list of them, as a new mock
+///   and answers its id
+/// - `GET /__admin/mocks` lists what the mocks do
+/// - `DELETE /__admin/mocks/{id}` removes the mock with the id
+/// - `POST /__admin/reset` removes all the mocks and clears the journal
+/// - `GET /__admin/requests` is the journal of the latest requests
+///
+/// Every request under `/__admin/` is answered here, a bad one with a 400, 404 or 405,
+/// so it never reaches the mocks.
+pub(crate) fn routes(
+    state: State,
+) -> impl Filter<Extract = (Box<dyn warp::Reply>,), Error = warp::Rejection> + Clone {
+    let add = warp::path!("replays")
+        .and(warp::post())
+        .and(with_sendable(state.clone()))
+        .and(warp::query())
+        .and(warp::body::json())
+        .map(add_replays);
+    let list = warp::path!("mocks")
+        .and(warp::get())
+        .and(with_sendable(state.clone()))
+        .map(list_mocks);
+    let remove = warp::path!("mocks" / u64)
+        .and(warp::delete())
+        .and(with_sendable(state.clone()))
+        .map(remove_mock);
+    let reset = warp::path!("reset")
+        .and(warp::post())
+        .and(with_sendable(state.clone()))
+        .map(reset);
+    let requests = warp::path!("requests")
+        .and(warp::get())
+        .and(with_sendable(state))
+        .map(journal);
+    let admin = add
+        .or(list)
+        .unify()
+        .or(remove)
+        .unify()
+        .or(reset)
+        .unify()
+        .or(requests)
+        .unify()
+        .recover(rejected)
+        .unify();
+    warp::path("__admin").and(admin)
+}
+
+/// The answer to a bad admin request
+async fn rejected(rejection: warp::Rejection) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let (status, message) = if let Some(error) = rejection.find::<BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, error.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "No such admin route".to_string())
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed".to_string(),
+        )
+    } else {
+        (StatusCode::BAD_REQUEST, format!("{:?}", rejection))
+    };
+    Ok(Box::new(warp::reply::with_status(message, status)))
+}
+
+fn add_replays(state: State, query: AddOptions, replays: Replays) -> Box<dyn warp::Reply> {
+    let replays = match replays {
+        Replays::Many(replays) => replays,
+        Replays::One(replay) => vec![*replay],
+    };
+    let added = replays.len();
+    let mut options = MockOptions::new();
+    if let Some(name) = &query.name {
+        options = options.name(name);
+    }
+    for tag in query.tags.iter().flat_map(|tags| tags.split(',')) {
+        options = options.tag(tag);
+    }
+    let id = state.add_mock(ReplayMock::new(replays), options);
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&json!({ "id": id, "added": added })),
+        StatusCode::CREATED,
+    ))
+}
+
+fn list_mocks(state: State) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::json(&state.mock_infos()))
+}
+
+fn remove_mock(id: u64, state: State) -> Box<dyn warp::Reply> {
+    if state.remove_mock(id) {
+        Box::new(StatusCode::NO_CONTENT)
+    } else {
+        Box::new(StatusCode::NOT_FOUND)
+    }
+}
+
+fn reset(state: State) -> Box<dyn warp::Reply> {
+    state.update_mocks(Vec::clear);
+    state.journal.take();
+    Box::new(StatusCode::NO_CONTENT)
+}
+
+fn journal(state: State) -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::json(&state.journal.entries()))
+}
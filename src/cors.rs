@@ -0,0 +1,147 @@
+use warp::{
+    http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode},
+    hyper::Body,
+};
+
+/// The CORS rules the mock server answers browsers with. Preflights are answered
+/// before any mock runs, and every response gets the CORS headers, whichever mock served it.
+/// By default any origin, method and header is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+}
+
+impl Cors {
+    /// Allow any origin, method and header
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Only allow the listed origins, like `http://localhost:3000`
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.to_string());
+        self
+    }
+    /// Only allow the listed methods in preflights, instead of any
+    pub fn allow_method(mut self, method: &str) -> Self {
+        self.methods.push(method.to_uppercase());
+        self
+    }
+    /// Only allow the listed request headers in preflights, instead of any
+    pub fn allow_header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_lowercase());
+        self
+    }
+    /// Let the browser read a response header, like `link` for pagination
+    pub fn expose_header(mut self, name: &str) -> Self {
+        self.exposed_headers.push(name.to_lowercase());
+        self
+    }
+    /// Allow cookies and auth headers, the origin is echoed back instead of `*`
+    pub fn allow_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.is_empty() {
+            Some(if self.credentials { origin } else { "*" }.to_string())
+        } else {
+            self.origins
+                .iter()
+                .find(|allowed| *allowed == origin)
+                .cloned()
+        }
+    }
+
+    /// The answer to a preflight, or None when the request isn't one
+    pub(crate) fn preflight(&self, method: &Method, headers: &HeaderMap) -> Option<Response<Body>> {
+        if method != Method::OPTIONS {
+            return None;
+        }
+        let origin = headers.get(header::ORIGIN)?.to_str().ok()?;
+        let requested_method = headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)?
+            .to_str()
+            .ok()?;
+        let requested_headers = headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        let method_allowed = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(requested_method));
+        if self.allowed_origin(origin).is_none() || !method_allowed {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .ok();
+        }
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .ok()?;
+        let response_headers = response.headers_mut();
+        let allowed_methods = if self.methods.is_empty() {
+            requested_method.to_string()
+        } else {
+            self.methods.join(", ")
+        };
+        set_header(
+            response_headers,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            &allowed_methods,
+        );
+        let allowed_headers = if self.headers.is_empty() {
+            requested_headers.to_string()
+        } else {
+            self.headers.join(", ")
+        };
+        if !allowed_headers.is_empty() {
+            set_header(
+                response_headers,
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                &allowed_headers,
+            );
+        }
+        self.decorate(origin, response_headers);
+        Some(response)
+    }
+
+    /// Add the CORS headers to the response of a request from the origin
+    pub(crate) fn decorate(&self, origin: &str, headers: &mut HeaderMap) {
+        let allowed_origin = match self.allowed_origin(origin) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        if allowed_origin != "*" {
+            set_header(headers, header::VARY, "origin");
+        }
+        set_header(
+            headers,
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            &allowed_origin,
+        );
+        if self.credentials {
+            set_header(headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if !self.exposed_headers.is_empty() {
+            set_header(
+                headers,
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                &self.exposed_headers.join(", "),
+            );
+        }
+    }
+}
+
+fn set_header(headers: &mut HeaderMap, name: header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
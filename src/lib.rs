@@ -2,28 +2,351 @@
 //! ### Purpose
 //! We want to to capture a proxy, and replay, and even pass it through if needed.
 use std::{
-    mem::replace,
+    collections::VecDeque,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use models::{DynamicBody, Method};
+use bytes::Buf;
+use cors::Cors;
+use futures_util::{stream, Stream, StreamExt};
+use models::{DynamicBody, JournalEntry, Method};
+use serde::Serialize;
 use serde_json::Value;
-use tokio::sync::oneshot;
-use tracing::warn;
+use tls::TlsCertificates;
+use tokio::{
+    runtime::{self, Runtime},
+    sync::oneshot,
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, field::Empty, info, info_span, warn, Instrument};
 use warp::{filters, Filter};
 
+mod admin;
+/// Cors is how the server answers browsers calling from another origin
+pub mod cors;
 /// Mocks are the ways that we can create route mocking
 /// There are usefull tools like gateway, which is a proxy
 /// and replay that can replay a json
 pub mod mocks;
 /// Models are the abstraction so that way we can simplify the types
 /// to the closure mock, and abstract out to any implmentation.
-pub mod models;
+pub use replay_mocker_models as models;
+/// Tls is the generated certificates, so the mock server can serve https
+pub mod tls;
+
+#[cfg(feature = "macros")]
+pub use replay_mocker_macros::embed_replays;
 
 type RunMock = Box<dyn mocks::RunMock + Send + Sync>;
 
-type Mocks = Arc<Mutex<Vec<Arc<RunMock>>>>;
+/// How a mock is added to the server: its name and tags, to find it again, and its priority
+#[derive(Debug, Clone, Default)]
+pub struct MockOptions {
+    name: Option<String>,
+    tags: Vec<String>,
+    priority: i32,
+    times: Option<u64>,
+}
+
+impl MockOptions {
+    /// No name, no tags and priority 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Name the mock, the journal says which mock answered by its name
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    /// Tag the mock, can be called more than once
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+    /// Mocks with a higher priority are tried first,
+    /// mocks with the same priority are tried in the order they were added
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+    /// Stop matching after answering this many requests, then the next mocks get them
+    pub fn times(mut self, times: u64) -> Self {
+        self.times = Some(times);
+        self
+    }
+    /// Only answer the first request, like `times(1)`
+    pub fn once(self) -> Self {
+        self.times(1)
+    }
+}
+
+/// What we know about a mock on the server, for debugging which mock answers what
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MockInfo {
+    /// The id the server gave the mock
+    pub id: u64,
+    /// The name from the options
+    pub name: Option<String>,
+    /// The tags from the options
+    pub tags: Vec<String>,
+    /// The priority from the options
+    pub priority: i32,
+    /// How many requests the mock answered, a limited mock counts the ones it is running too
+    pub hits: u64,
+    /// How many requests the mock answers at most, when it is limited
+    pub times: Option<u64>,
+    /// What the mock says it does
+    pub description: Value,
+}
+
+/// A mock on the server, with what we need to find it again and order it
+#[derive(Clone)]
+struct MockEntry {
+    id: u64,
+    options: MockOptions,
+    hits: Arc<AtomicU64>,
+    mock: Arc<RunMock>,
+}
+
+impl MockEntry {
+    fn info(&self) -> MockInfo {
+        MockInfo {
+            id: self.id,
+            name: self.options.name.clone(),
+            tags: self.options.tags.clone(),
+            priority: self.options.priority,
+            hits: self.hits.load(Ordering::SeqCst),
+            times: self.options.times,
+            description: self.mock.describe(),
+        }
+    }
+
+    /// Take a hit of a limited mock before running it, so requests racing for it can't
+    /// run it more times than it allows. False when it is used up.
+    fn reserve_hit(&self) -> bool {
+        match self.options.times {
+            Some(times) => self
+                .hits
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |hits| {
+                    (hits < times).then_some(hits + 1)
+                })
+                .is_ok(),
+            None => true,
+        }
+    }
+
+    /// The mock answered, a limited mock counted the hit when it was reserved
+    fn count_hit(&self) {
+        if self.options.times.is_none() {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The mock passed on the request, so a reserved hit is given back
+    fn release_hit(&self) {
+        if self.options.times.is_some() {
+            self.hits.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// How many requests the journal keeps unless told otherwise
+const JOURNAL_LIMIT: usize = 1000;
+
+/// The requests the server got, only the latest ones are kept so a busy or long
+/// running server doesn't grow without end. Requests send their entry on a channel
+/// and never wait for the lock, whoever gets it moves the sent entries in.
+struct Journal {
+    sender: Sender<JournalEntry>,
+    limit: AtomicUsize,
+    kept: Mutex<Kept>,
+}
+
+/// The entries moved in from the channel, and the channel they come from
+struct Kept {
+    arrived: Receiver<JournalEntry>,
+    entries: VecDeque<JournalEntry>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        let (sender, arrived) = mpsc::channel();
+        Self {
+            sender,
+            limit: AtomicUsize::new(JOURNAL_LIMIT),
+            kept: Mutex::new(Kept {
+                arrived,
+                entries: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl Journal {
+    /// Keep the entry, dropping the oldest ones over the limit
+    fn push(&self, entry: JournalEntry) {
+        if self.limit.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let _ = self.sender.send(entry);
+        if let Ok(mut kept) = self.kept.try_lock() {
+            self.keep(&mut kept);
+        }
+    }
+
+    /// The kept entries, with the ones sent so far moved in
+    fn kept(&self) -> MutexGuard<'_, Kept> {
+        let mut kept = self.kept.lock().unwrap();
+        self.keep(&mut kept);
+        kept
+    }
+
+    /// Move in the entries sent so far, dropping the oldest ones over the limit
+    fn keep(&self, kept: &mut Kept) {
+        let limit = self.limit.load(Ordering::SeqCst);
+        kept.entries.extend(kept.arrived.try_iter());
+        let over = kept.entries.len().saturating_sub(limit);
+        kept.entries.drain(..over);
+    }
+
+    fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::SeqCst);
+        self.keep(&mut self.kept.lock().unwrap());
+    }
+
+    fn entries(&self) -> Vec<JournalEntry> {
+        self.kept().entries.iter().cloned().collect()
+    }
+
+    fn take(&self) -> Vec<JournalEntry> {
+        self.kept().entries.drain(..).collect()
+    }
+}
+
+/// What the server shares with the requests it is answering
+#[derive(Default)]
+struct ServerState {
+    /// Highest priority first, and in the order added for the same priority.
+    /// Requests take a snapshot of the list, changes swap in a new list, so requests
+    /// never wait on each other and only briefly on a change.
+    mocks: RwLock<Arc<Vec<MockEntry>>>,
+    next_id: AtomicU64,
+    journal: Journal,
+    cors: Mutex<Option<Cors>>,
+    /// The runtime the server runs on, for the background work of the mocks
+    runtime: Option<runtime::Handle>,
+    /// The websocket sessions, they outlive the request that upgraded them
+    sessions: TaskTracker,
+    /// Tells the websocket sessions the server is shutting down
+    closing: CancellationToken,
+}
+
+type State = Arc<ServerState>;
+
+impl ServerState {
+    /// The mocks as they are now, changes after this don't show up in it
+    fn mocks(&self) -> Arc<Vec<MockEntry>> {
+        self.mocks.read().unwrap().clone()
+    }
+
+    /// Change the mocks, requests already running keep the list they started with
+    fn update_mocks<R>(&self, update: impl FnOnce(&mut Vec<MockEntry>) -> R) -> R {
+        let mut mocks = self.mocks.write().unwrap();
+        update(Arc::make_mut(&mut mocks))
+    }
+
+    /// Start the mock on the server's runtime, ready to be put in the list
+    fn started(&self, mock: RunMock) -> Arc<RunMock> {
+        if let Some(runtime) = &self.runtime {
+            mock.start(runtime);
+        }
+        Arc::new(mock)
+    }
+
+    fn add_mock(&self, mock: RunMock, options: MockOptions) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mock = self.started(mock);
+        self.update_mocks(|mocks| {
+            let index = mocks
+                .iter()
+                .position(|entry| entry.options.priority < options.priority)
+                .unwrap_or(mocks.len());
+            mocks.insert(
+                index,
+                MockEntry {
+                    id,
+                    options,
+                    hits: Default::default(),
+                    mock,
+                },
+            );
+        });
+        id
+    }
+
+    /// Take the mock out of the list, false if it was already gone
+    fn remove_mock(&self, id: u64) -> bool {
+        self.update_mocks(|mocks| {
+            let before = mocks.len();
+            mocks.retain(|entry| entry.id != id);
+            mocks.len() != before
+        })
+    }
+
+    fn mock_infos(&self) -> Vec<MockInfo> {
+        self.mocks().iter().map(MockEntry::info).collect()
+    }
+}
+
+/// A handle on a mock added to a running server, to remove or replace just that mock.
+/// It works from `&self`, so the server can be shared while the test changes it.
+#[derive(Clone)]
+pub struct MockHandle {
+    id: u64,
+    state: State,
+}
+
+impl MockHandle {
+    /// Stop the server from using this mock, false if it was already gone
+    pub fn remove(&self) -> bool {
+        self.state.remove_mock(self.id)
+    }
+
+    /// Swap in another mock at the same place and priority, false if it was already gone
+    pub fn replace(&self, mock: RunMock) -> bool {
+        let mock = self.state.started(mock);
+        self.state
+            .update_mocks(|mocks| match mocks.iter_mut().find(|x| x.id == self.id) {
+                Some(entry) => {
+                    entry.mock = mock;
+                    true
+                }
+                None => false,
+            })
+    }
+
+    /// What we know about the mock, None once it was removed
+    pub fn info(&self) -> Option<MockInfo> {
+        self.state
+            .mocks()
+            .iter()
+            .find(|entry| entry.id == self.id)
+            .map(MockEntry::info)
+    }
+
+    /// How many requests the mock answered, 0 once it was removed
+    pub fn hits(&self) -> u64 {
+        self.info().map(|info| info.hits).unwrap_or_default()
+    }
+}
 
 #[derive(Debug, Clone)]
 enum ResultType {
@@ -34,41 +357,111 @@ enum ResultType {
 /// Mock Server is the main piece, this will start a server on a random port
 /// and we can get the port and url. We then can modify behaviour with the mocks.
 pub struct MockServer {
-    mocks: Mocks,
+    state: State,
     /// Address where the server is hosting.
     pub address: SocketAddr,
+    /// The certificates when we are serving https, trust the `ca_pem` in the client.
+    pub tls: Option<TlsCertificates>,
     kill: Option<oneshot::Sender<()>>,
+    server: Option<JoinHandle<()>>,
+    /// The runtime the server owns, when it was started outside of one
+    runtime: Option<Runtime>,
 }
 async fn router(
-    mocks: Mocks,
+    state: State,
     path: String,
     queries: Option<String>,
     method: Method,
+    headers: Vec<(String, String)>,
     body: Option<DynamicBody>,
 ) -> ResultType {
     let request = models::Request {
         method,
         queries,
         path,
+        headers,
         body,
     };
-    let mocks = mocks.lock().unwrap().clone();
-    for mock in mocks.iter() {
-        let mock_result = mock.run_mock(&request).await;
+    let span = info_span!(
+        "request",
+        method = %request.method.as_method_string(),
+        path = %request.path,
+        mock_id = Empty,
+        mock_name = Empty,
+        upstream_status = Empty,
+    );
+    select_mock(state, request).instrument(span).await
+}
+
+/// Try the mocks in order inside the request span, logging why the ones before
+/// the answer passed on the request
+async fn select_mock(state: State, mut request: models::Request) -> ResultType {
+    let started = Instant::now();
+    for entry in state.mocks().iter() {
+        if !entry.mock.takes_streamed_body() {
+            request.buffer_body().await;
+        }
+        let name = entry.options.name.as_deref().unwrap_or_default();
+        if !entry.reserve_hit() {
+            debug!(
+                mock_id = entry.id,
+                mock_name = name,
+                "Skipped mock, it is used up"
+            );
+            continue;
+        }
+        let mock_result = entry.mock.run_mock(&request).await;
         if let Some(value) = mock_result {
+            entry.count_hit();
+            let span = tracing::Span::current();
+            span.record("mock_id", entry.id);
+            span.record("mock_name", name);
+            info!(
+                latency_ms = started.elapsed().as_millis() as u64,
+                "Mock answered"
+            );
+            state.journal.push(JournalEntry {
+                request: journaled(request),
+                matched: true,
+                mock_id: Some(entry.id),
+                mock_name: entry.options.name.clone(),
+            });
             return ResultType::Ok { value };
         }
+        entry.release_hit();
+        debug!(mock_id = entry.id, mock_name = name, "Mock didn't match");
     }
+    info!(
+        latency_ms = started.elapsed().as_millis() as u64,
+        "No mock matched"
+    );
+    state.journal.push(JournalEntry {
+        request: journaled(request),
+        matched: false,
+        mock_id: None,
+        mock_name: None,
+    });
     ResultType::NotFound
 }
 
+/// The request as the journal keeps it, a body still streaming to a mock can't be kept
+fn journaled(mut request: models::Request) -> models::Request {
+    if let Some(DynamicBody::Stream(_)) = request.body {
+        request.body = None;
+    }
+    request
+}
+
 impl Drop for MockServer {
     fn drop(&mut self) {
-        let kill = replace(&mut self.kill, None);
-        if let Some(kill) = kill {
-            kill.send(())
-                .expect("Sending kill signal for cleanup of mock server");
-            // self.server_task.
+        // The server may be gone already, then there is nothing left to stop
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.send(());
+        }
+        // Websocket sessions aren't part of the server task, so they are told to close too
+        self.state.closing.cancel();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
         }
     }
 }
@@ -78,14 +471,29 @@ fn with_sendable<V: Clone + Send + Sync>(
 ) -> impl Filter<Extract = (V,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || value.clone())
 }
-async fn route(
-    mocks: Mocks,
+/// The raw query string, if the request has one
+fn optional_query(
+) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+    filters::query::raw()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+}
+fn header_pairs(headers: &warp::http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+async fn route<B: Buf + Send>(
+    state: State,
     path: warp::filters::path::FullPath,
     queries: Option<String>,
-    body: Option<DynamicBody>,
+    headers: warp::http::HeaderMap,
     method: warp::http::Method,
+    body: impl Stream<Item = Result<B, warp::Error>> + Send + 'static,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    let method: Method = match method {
+    let method = match method {
         warp::http::Method::OPTIONS => Method::Options,
         warp::http::Method::PATCH => Method::Patch,
         warp::http::Method::POST => Method::Post,
@@ -95,142 +503,521 @@ async fn route(
         warp::http::Method::GET => Method::Get,
         warp::http::Method::DELETE => Method::Delete,
         warp::http::Method::CONNECT => Method::Connect,
-        _ => Method::Other,
+        method => Method::Other(method.as_str().to_string()),
+    };
+    let body = body.map(|chunk| {
+        chunk
+            .map(|mut chunk| chunk.copy_to_bytes(chunk.remaining()))
+            .map_err(std::io::Error::other)
+    });
+    let is_grpc = headers
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/grpc"));
+    let body = if is_grpc {
+        Some(DynamicBody::Stream(models::BodyStream::new(body)))
+    } else {
+        let mut bytes = vec![];
+        let mut body = Box::pin(body);
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => bytes.extend_from_slice(&chunk),
+                Err(error) => {
+                    warn!("Can't read the body for {}: {}", path.as_str(), error);
+                    return Ok(Box::new(warp::http::StatusCode::BAD_REQUEST));
+                }
+            }
+        }
+        request_body(&headers, bytes.into())
     };
     let path = path.as_str();
-    let routed = router(mocks, path.to_string(), queries, method, body.clone()).await;
+    let routed = router(
+        state,
+        path.to_string(),
+        queries,
+        method.clone(),
+        header_pairs(&headers),
+        body.clone(),
+    )
+    .await;
     match routed {
-        ResultType::Ok {
-            value: DynamicBody::Json(value),
-        } => Ok(Box::new(warp::reply::json(&value))),
-        ResultType::Ok {
-            value: DynamicBody::Text(text),
-        } => Ok(Box::new(warp::hyper::Response::builder().body(text))),
-        ResultType::Ok {
-            value: DynamicBody::Bytes(bytes),
-        } => Ok(Box::new(warp::hyper::Response::builder().body(bytes))),
+        ResultType::Ok { value } => reply(path, value).await,
+        // Answered here, a rejection would have warp try the request on other routes
         ResultType::NotFound => {
             warn!(
-                "\"Can't find route {:?}@{} with body {:?} \"",
-                method, path, body,
+                "\"Can't find route {}@{} with body {:?} \"",
+                method.as_method_string(),
+                path,
+                body,
             );
-            Err(warp::reject::not_found())
+            Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
         }
     }
 }
-async fn json_body_route(
-    mocks: Mocks,
-    path: warp::filters::path::FullPath,
-    queries: String,
-    body: Value,
-    method: warp::http::Method,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    route(
-        mocks,
-        path,
-        Some(queries),
-        Some(DynamicBody::Json(body)),
-        method,
-    )
-    .await
+/// Json when the body parses as json and isn't sent as something else, like warp's json
+/// filter, otherwise the raw bytes. An empty body is no body.
+fn request_body(headers: &warp::http::HeaderMap, body: bytes::Bytes) -> Option<DynamicBody> {
+    if body.is_empty() {
+        return None;
+    }
+    let json_allowed = headers
+        .get("content-type")
+        .and_then(|x| x.to_str().ok())
+        .is_none_or(|x| x.contains("json"));
+    match serde_json::from_slice(&body) {
+        Ok(json) if json_allowed => Some(DynamicBody::Json(json)),
+        _ => Some(DynamicBody::Bytes(body.to_vec())),
+    }
 }
-async fn bytes_body_route(
-    mocks: Mocks,
-    path: warp::filters::path::FullPath,
-    queries: String,
-    body: bytes::Bytes,
-    method: warp::http::Method,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    route(
-        mocks,
-        path,
-        Some(queries),
-        Some(DynamicBody::Bytes(body.into_iter().collect())),
-        method,
+async fn reply(path: &str, value: DynamicBody) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match value {
+        DynamicBody::Json(value) => Ok(Box::new(warp::reply::json(&value))),
+        DynamicBody::Text(text) => Ok(Box::new(warp::hyper::Response::builder().body(text))),
+        DynamicBody::Bytes(bytes) => Ok(Box::new(warp::hyper::Response::builder().body(bytes))),
+        DynamicBody::Chunks(chunks) => {
+            let stream = stream::iter(chunks).then(|chunk| async move {
+                sleep(Duration::from_millis(chunk.delay_ms)).await;
+                Ok::<_, std::io::Error>(bytes::Bytes::from(chunk.data))
+            });
+            Ok(Box::new(warp::hyper::Response::new(
+                warp::hyper::Body::wrap_stream(stream),
+            )))
+        }
+        DynamicBody::Events(events) => {
+            let stream = stream::iter(events).then(|event| async move {
+                sleep(Duration::from_millis(event.delay_ms)).await;
+                Ok::<_, std::io::Error>(bytes::Bytes::from(event.to_wire()))
+            });
+            Ok(Box::new(
+                warp::hyper::Response::builder()
+                    .header("content-type", "text/event-stream")
+                    .header("cache-control", "no-cache")
+                    .body(warp::hyper::Body::wrap_stream(stream)),
+            ))
+        }
+        DynamicBody::File(file_path) => match tokio::fs::File::open(&file_path).await {
+            Ok(file) => Ok(Box::new(warp::hyper::Response::new(
+                warp::hyper::Body::wrap_stream(ReaderStream::new(file)),
+            ))),
+            // Answered here, a rejection would have warp route the request a second time
+            Err(error) => {
+                warn!("Can't open file {:?} for {}: {}", file_path, path, error);
+                Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        },
+        DynamicBody::Stream(body_stream) => match body_stream.take() {
+            Some(stream) => {
+                let mut response = warp::hyper::Response::builder();
+                if let Some(content_type) = &body_stream.content_type {
+                    response = response.header("content-type", content_type);
+                }
+                let body = match body_stream.take_trailers() {
+                    Some(trailers) => with_trailers(stream, trailers),
+                    None => warp::hyper::Body::wrap_stream(stream),
+                };
+                Ok(Box::new(response.body(body)))
+            }
+            None => {
+                warn!("Stream for {} was already consumed", path);
+                Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        },
+        DynamicBody::GrpcFrames(frames) => Ok(grpc_reply(frames)),
+        DynamicBody::Grpc(response) if response.messages.is_empty() => {
+            Ok(grpc_reply(models::GrpcFrames {
+                messages: vec![],
+                status: response.status,
+            }))
+        }
+        DynamicBody::Grpc(_) => {
+            warn!(
+                "gRPC messages for {} need descriptors, use a GrpcMock",
+                path
+            );
+            Ok(grpc_reply(models::GrpcFrames {
+                messages: vec![],
+                status: models::GrpcStatus::error(13, "no descriptors to encode the response"),
+            }))
+        }
+        DynamicBody::WithStatus { status, body } => {
+            let mut response = warp::Reply::into_response(Box::pin(reply(path, *body)).await?);
+            match warp::http::StatusCode::from_u16(status) {
+                Ok(status) => *response.status_mut() = status,
+                Err(_) => warn!("Skipping invalid status {} for {}", status, path),
+            }
+            Ok(Box::new(response))
+        }
+        DynamicBody::WithHeaders { headers, body } => {
+            let mut response = warp::Reply::into_response(Box::pin(reply(path, *body)).await?);
+            let response_headers = response.headers_mut();
+            for (name, _) in headers.iter() {
+                response_headers.remove(name.as_str());
+            }
+            for (name, value) in headers.iter() {
+                match (
+                    warp::http::header::HeaderName::from_bytes(name.as_bytes()),
+                    warp::http::HeaderValue::from_str(value),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        response_headers.append(name, value);
+                    }
+                    _ => warn!("Skipping invalid header {}: {}", name, value),
+                }
+            }
+            Ok(Box::new(response))
+        }
+        DynamicBody::WebSocket(_) | DynamicBody::WebSocketProxy(_) => {
+            Ok(Box::new(warp::reply::with_status(
+                "Expected a websocket upgrade",
+                warp::http::StatusCode::UPGRADE_REQUIRED,
+            )))
+        }
+    }
+}
+/// A body that sends the trailers after the stream, unless the stream breaks
+fn with_trailers(
+    stream: impl Stream<Item = Result<bytes::Bytes, std::io::Error>> + Send + 'static,
+    trailers: oneshot::Receiver<warp::http::HeaderMap>,
+) -> warp::hyper::Body {
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        let mut stream = Box::pin(stream);
+        while let Some(chunk) = stream.next().await {
+            let sent = match chunk {
+                Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                sender.abort();
+                return;
+            }
+        }
+        if let Ok(trailers) = trailers.await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    body
+}
+fn grpc_reply(frames: models::GrpcFrames) -> Box<dyn warp::Reply> {
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        for message in frames.messages {
+            let frame = models::encode_grpc_frame(&message);
+            if sender.send_data(frame.into()).await.is_err() {
+                return;
+            }
+        }
+        let mut trailers = warp::http::HeaderMap::new();
+        trailers.insert("grpc-status", frames.status.code.into());
+        let metadata = frames
+            .status
+            .message
+            .iter()
+            .map(|message| ("grpc-message", message))
+            .chain(
+                frames
+                    .status
+                    .trailers
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value)),
+            );
+        for (key, value) in metadata {
+            match (
+                warp::http::header::HeaderName::from_bytes(key.as_bytes()),
+                warp::http::HeaderValue::from_str(value),
+            ) {
+                (Ok(key), Ok(value)) => {
+                    trailers.insert(key, value);
+                }
+                _ => warn!("Skipping invalid gRPC trailer {}: {}", key, value),
+            }
+        }
+        let _ = sender.send_trailers(trailers).await;
+    });
+    Box::new(
+        warp::hyper::Response::builder()
+            .header("content-type", "application/grpc")
+            .body(body),
     )
-    .await
 }
-async fn json_body_route_no_queries(
-    mocks: Mocks,
-    path: warp::filters::path::FullPath,
-    body: Value,
+async fn cors_preflight(
+    state: State,
     method: warp::http::Method,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    route(mocks, path, None, Some(DynamicBody::Json(body)), method).await
+    headers: warp::http::HeaderMap,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let cors = state.cors.lock().unwrap().clone();
+    cors.and_then(|cors| cors.preflight(&method, &headers))
+        .ok_or_else(warp::reject::not_found)
 }
-async fn no_body_route(
-    mocks: Mocks,
-    path: warp::filters::path::FullPath,
-    queries: String,
-    method: warp::http::Method,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    route(mocks, path, Some(queries), None, method).await
+fn cors_decorate(
+    state: State,
+    origin: Option<String>,
+    reply: impl warp::Reply,
+) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if let (Some(cors), Some(origin)) = (state.cors.lock().unwrap().as_ref(), origin) {
+        cors.decorate(&origin, response.headers_mut());
+    }
+    response
 }
-async fn no_body_route_no_queries(
-    mocks: Mocks,
+async fn ws_route(
+    state: State,
     path: warp::filters::path::FullPath,
-    method: warp::http::Method,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    route(mocks, path, None, None, method).await
+    queries: Option<String>,
+    headers: warp::http::HeaderMap,
+    ws: warp::ws::Ws,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let path = path.as_str();
+    let routed = router(
+        state.clone(),
+        path.to_string(),
+        queries,
+        Method::Get,
+        header_pairs(&headers),
+        None,
+    )
+    .await;
+    match routed {
+        ResultType::Ok {
+            value: DynamicBody::WebSocket(steps),
+        } => {
+            let (sessions, closing) = (state.sessions.clone(), state.closing.clone());
+            Ok(Box::new(ws.on_upgrade(move |socket| {
+                sessions.track_future(mocks::run_ws_script(socket, steps, closing))
+            })))
+        }
+        ResultType::Ok {
+            value: DynamicBody::WebSocketProxy(proxy),
+        } => {
+            let (sessions, closing) = (state.sessions.clone(), state.closing.clone());
+            Ok(Box::new(ws.on_upgrade(move |socket| {
+                sessions.track_future(mocks::run_ws_proxy(socket, *proxy, closing))
+            })))
+        }
+        ResultType::Ok { value } => reply(path, value).await,
+        // Answered here, so the request isn't routed a second time as a plain one
+        ResultType::NotFound => {
+            warn!("\"Can't find websocket route {}\"", path);
+            Ok(Box::new(warp::http::StatusCode::NOT_FOUND))
+        }
+    }
+}
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MockServer {
-    /// Notes: Creating a on a random port
+    /// Notes: Creating a mock server on a random port
     pub fn new() -> MockServer {
+        Self::start(None)
+    }
+
+    /// Notes: Creating a mock server on a random port, serving https with a newly generated CA
+    pub fn new_https() -> MockServer {
+        Self::new_https_with(TlsCertificates::generate())
+    }
+
+    /// Notes: Creating a mock server on a random port, serving https with the given certificates.
+    /// Useful to share one CA between servers.
+    pub fn new_https_with(certificates: TlsCertificates) -> MockServer {
+        Self::start(Some(certificates))
+    }
+
+    /// Notes: Creating a mock server on a random port, for code without a tokio runtime like a plain
+    /// `#[test]` with a blocking client. The server runs on its own runtime in a background
+    /// thread, which stops with the server.
+    pub fn new_blocking() -> MockServer {
+        Self::start_blocking(None)
+    }
+
+    /// Notes: Like `new_blocking`, serving https with a newly generated CA
+    pub fn new_https_blocking() -> MockServer {
+        Self::start_blocking(Some(TlsCertificates::generate()))
+    }
+
+    fn start_blocking(tls: Option<TlsCertificates>) -> MockServer {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("replay-mocker")
+            .enable_all()
+            .build()
+            .expect("Building the mock server runtime");
+        let mut mock = {
+            let _runtime = runtime.enter();
+            Self::start(tls)
+        };
+        mock.runtime = Some(runtime);
+        mock
+    }
+
+    fn start(tls: Option<TlsCertificates>) -> MockServer {
         let addr: SocketAddr = ([0, 0, 0, 0], 0).into();
-        let mocks: Mocks = Default::default();
+        let state: State = Arc::new(ServerState {
+            runtime: Some(runtime::Handle::current()),
+            ..Default::default()
+        });
 
         let service = {
-            with_sendable(mocks.clone())
+            with_sendable(state.clone())
                 .and(filters::path::full())
-                .and(filters::query::raw())
-                .and(filters::body::json())
-                .and(filters::method::method())
-                .and_then(json_body_route)
-                .or(with_sendable(mocks.clone())
-                    .and(filters::path::full())
-                    .and(filters::query::raw())
-                    .and(filters::body::bytes())
-                    .and(filters::method::method())
-                    .and_then(bytes_body_route))
-                .or(with_sendable(mocks.clone())
-                    .and(filters::path::full())
-                    .and(filters::query::raw())
-                    .and(filters::method::method())
-                    .and_then(no_body_route))
-                .or(with_sendable(mocks.clone())
-                    .and(filters::path::full())
-                    .and(filters::body::json())
-                    .and(filters::method::method())
-                    .and_then(json_body_route_no_queries))
-                .or(with_sendable(mocks.clone())
+                .and(optional_query())
+                .and(filters::header::headers_cloned())
+                .and(warp::ws())
+                .and_then(ws_route)
+                .or(with_sendable(state.clone())
                     .and(filters::path::full())
+                    .and(optional_query())
+                    .and(filters::header::headers_cloned())
                     .and(filters::method::method())
-                    .and_then(no_body_route_no_queries))
+                    .and(filters::body::stream())
+                    .and_then(route))
         };
+        let service = with_sendable(state.clone())
+            .and(filters::method::method())
+            .and(filters::header::headers_cloned())
+            .and_then(cors_preflight)
+            .or(admin::routes(state.clone()))
+            .or(service);
+        let service = with_sendable(state.clone())
+            .and(filters::header::optional::<String>("origin"))
+            .and(service)
+            .map(cors_decorate);
         let (s, r) = oneshot::channel();
+        let shutdown = async {
+            let _ = r.await;
+        };
 
-        let (address, server) = warp::serve(service).bind_with_graceful_shutdown(addr, async {
-            r.await.unwrap();
-        });
-        tokio::spawn(server);
-        println!("Starting server on {}", address);
+        let (address, server) = match &tls {
+            Some(certificates) => {
+                let (address, server) = warp::serve(service)
+                    .tls()
+                    .cert(&certificates.cert_pem)
+                    .key(&certificates.key_pem)
+                    .bind_with_graceful_shutdown(addr, shutdown);
+                (address, tokio::spawn(server))
+            }
+            None => {
+                let (address, server) =
+                    warp::serve(service).bind_with_graceful_shutdown(addr, shutdown);
+                (address, tokio::spawn(server))
+            }
+        };
+        info!("Mock server listening on {}", address);
         MockServer {
-            mocks,
+            state,
             address,
+            tls,
             kill: Some(s),
+            server: Some(server),
+            runtime: None,
+        }
+    }
+
+    /// Stop the server, waiting for the requests it is answering to finish, then save
+    /// what the gateways recorded. Returns the requests the server got.
+    /// Open websockets are closed, and a proxied one is recorded up to there.
+    /// Dropping the server stops it too, without waiting.
+    pub async fn shutdown(mut self) -> Vec<JournalEntry> {
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.send(());
+        }
+        if let Some(server) = self.server.take() {
+            if let Err(error) = server.await {
+                warn!("Mock server on {} stopped badly: {}", self.address, error);
+            }
+        }
+        self.state.closing.cancel();
+        self.state.sessions.close();
+        self.state.sessions.wait().await;
+        for entry in self.state.mocks().iter() {
+            entry.mock.flush();
         }
+        self.state.journal.take()
+    }
+
+    /// `shutdown` for a server made with `new_blocking`, from code without a runtime
+    pub fn shutdown_blocking(mut self) -> Vec<JournalEntry> {
+        let runtime = self
+            .runtime
+            .take()
+            .expect("shutdown_blocking needs a server made with new_blocking");
+        let journal = runtime.block_on(self.shutdown());
+        runtime.shutdown_background();
+        journal
     }
 
     /// Use this to change the behaviour of the server, adding in a replay.
     pub fn with_mock(self, mock: RunMock) -> Self {
-        self.mocks.lock().unwrap().push(Arc::new(mock));
+        self.add_mock(mock);
+        self
+    }
+
+    /// Add a mock to the running server, the handle can remove or replace it later
+    pub fn add_mock(&self, mock: RunMock) -> MockHandle {
+        self.add_mock_with(mock, MockOptions::new())
+    }
+
+    /// Add a mock that is tried before the mocks with a lower priority,
+    /// mocks with the same priority are tried in the order they were added
+    pub fn add_mock_with_priority(&self, mock: RunMock, priority: i32) -> MockHandle {
+        self.add_mock_with(mock, MockOptions::new().priority(priority))
+    }
+
+    /// Add a mock with a name, tags or priority
+    pub fn add_mock_with(&self, mock: RunMock, options: MockOptions) -> MockHandle {
+        MockHandle {
+            id: self.state.add_mock(mock, options),
+            state: self.state.clone(),
+        }
+    }
+
+    /// The mocks, in the order they are tried, with their hit counts
+    pub fn mocks(&self) -> Vec<MockInfo> {
+        self.state.mock_infos()
+    }
+
+    /// The mocks with the tag, in the order they are tried
+    pub fn mocks_tagged(&self, tag: &str) -> Vec<MockInfo> {
+        self.mocks()
+            .into_iter()
+            .filter(|info| info.tags.iter().any(|x| x == tag))
+            .collect()
+    }
+
+    /// Remove the mocks with the tag, and say how many were removed
+    pub fn remove_mocks_tagged(&self, tag: &str) -> usize {
+        self.state.update_mocks(|mocks| {
+            let before = mocks.len();
+            mocks.retain(|entry| !entry.options.tags.iter().any(|x| x == tag));
+            before - mocks.len()
+        })
+    }
+
+    /// Answer CORS preflights and add the CORS headers to every response,
+    /// for browsers calling from another origin.
+    pub fn with_cors(self, cors: Cors) -> Self {
+        *self.state.cors.lock().unwrap() = Some(cors);
+        self
+    }
+
+    /// Keep this many of the latest requests in the journal, 1000 by default.
+    /// A limit of 0 turns the journal off, for servers answering lots of requests.
+    pub fn with_journal_limit(self, limit: usize) -> Self {
+        self.state.journal.set_limit(limit);
         self
     }
 
+    /// The latest requests the server got, and if a mock answered them
+    pub fn journal(&self) -> Vec<JournalEntry> {
+        self.state.journal.entries()
+    }
+
     /// Use this to change the behaviour of the server, adding in a replay.
     pub fn url(&self, path: &str) -> String {
-        format!("http://{}/{}", self.address, path)
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}/{}", scheme, self.address, path)
     }
 
     /// Use this to change the behaviour of the server, filter out in a replay.
@@ -238,14 +1025,8 @@ impl MockServer {
     where
         Filter: Fn(&Arc<RunMock>) -> bool,
     {
-        {
-            let mut filters = self.mocks.lock().unwrap();
-            *filters = filters
-                .iter()
-                .map(|x| x.clone())
-                .filter(move |x| filter(x))
-                .collect::<Vec<_>>();
-        }
+        self.state
+            .update_mocks(|mocks| mocks.retain(|entry| filter(&entry.mock)));
         self
     }
 }
@@ -254,19 +1035,71 @@ impl MockServer {
 mod tests {
     use bytes::Bytes;
     use core::time::Duration;
-    use std::{fs::remove_file, time::Instant};
+    use std::{fs::remove_file, sync::Arc, time::Instant};
     use tokio::{
         sync::{mpsc, oneshot},
         time::timeout,
     };
+    use warp::Filter;
 
     use crate::{
+        cors::Cors,
         mocks::Gateway,
-        mocks::{ClosureMock, FactoryClosure, ReplayMock},
-        MockServer,
+        mocks::{
+            ClosureMock, FactoryClosure, GraphQlMock, GrpcDescriptors, GrpcGateway, GrpcMock,
+            ReplayMock, SseMock, WebSocketGateway, WebSocketMock,
+        },
+        models::{
+            decode_grpc_frames, encode_grpc_frame, BodyStream, Cassettes, Chunk, DynamicBody,
+            GrpcResponse, HttpVersion, JournalEntry, Method, Redaction, Replay, Request, SseEvent,
+            WsStep, REDACTED,
+        },
+        tls::TlsCertificates,
+        MockOptions, MockServer,
     };
+    use futures_util::{SinkExt, StreamExt};
     use serde_json::{json, Value};
     use tokio::{self, task};
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    /// The logs written on this thread while the guard is held
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Logs {
+        fn capture() -> (Self, tracing::subscriber::DefaultGuard) {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            (logs, tracing::subscriber::set_default(subscriber))
+        }
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).expect("utf-8 logs")
+        }
+    }
+
+    /// Wait for the check to pass, for things done in the background like reloads
+    async fn eventually<F: std::future::Future<Output = bool>>(mut check: impl FnMut() -> F) {
+        timeout(Duration::from_secs(5), async {
+            while !check().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Check passed in time");
+    }
 
     #[tokio::test]
     async fn capture_and_replay() {
@@ -456,4 +1289,2152 @@ mod tests {
         let body_two = body_two.await.unwrap();
         assert!(body_one > body_two);
     }
+
+    #[tokio::test]
+    async fn chunked_body_test() {
+        let mock = MockServer::new().with_mock(ClosureMock::new(|_req| async {
+            Some(DynamicBody::Chunks(vec![
+                Chunk::new(0, "first "),
+                Chunk::new(50, "second "),
+                Chunk::new(50, "third"),
+            ]))
+        }));
+        let start = Instant::now();
+        let mut res = reqwest::get(&mock.url("progress"))
+            .await
+            .expect("Valid get");
+        let mut chunks = vec![];
+        while let Some(chunk) = res.chunk().await.expect("chunk") {
+            chunks.push(chunk);
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(chunks.concat(), b"first second third".to_vec());
+    }
+
+    #[tokio::test]
+    async fn file_body_test() {
+        let file_path = std::env::temp_dir().join("replay-mocker-file-body.bin");
+        let contents: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+        std::fs::write(&file_path, &contents).expect("Write the file to stream");
+        let mock = MockServer::new().with_mock(ClosureMock::new({
+            let file_path = file_path.clone();
+            move |_req| {
+                let file_path = file_path.clone();
+                async move { Some(DynamicBody::File(file_path)) }
+            }
+        }));
+
+        let body = reqwest::get(&mock.url("download"))
+            .await
+            .expect("Valid get")
+            .bytes()
+            .await
+            .expect("bytes");
+
+        assert_eq!(body.to_vec(), contents);
+        remove_file(file_path).expect("Remove the file for the testing");
+
+        // A missing file is a server error, and a websocket upgrade isn't routed twice for it
+        let res = reqwest::get(&mock.url("download"))
+            .await
+            .expect("Valid get");
+        assert_eq!(res.status(), 500);
+        let res = reqwest::Client::new()
+            .get(mock.url("download"))
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .send()
+            .await
+            .expect("Valid get");
+        assert_eq!(res.status(), 500);
+        assert_eq!(mock.journal().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gateway_streams_and_records_chunks() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-stream.json");
+        let file_path = file_path.to_str().unwrap();
+        let big = vec![b'x'; 2 * 1024 * 1024];
+        let upstream_big = big.clone();
+        // The rest of the body waits until the client has the first chunk
+        let first_arrived = Arc::new(tokio::sync::Notify::new());
+        let upstream_arrived = first_arrived.clone();
+        let upstream = MockServer::new().with_mock(ClosureMock::new(move |req| {
+            let big = upstream_big.clone();
+            let arrived = upstream_arrived.clone();
+            let body = if req.path == "/big" {
+                DynamicBody::Bytes(big)
+            } else {
+                let first = futures_util::stream::once(async { Ok(Bytes::from("streamed ")) });
+                let rest = futures_util::stream::once(async move {
+                    arrived.notified().await;
+                    Ok(Bytes::from("body"))
+                });
+                DynamicBody::Stream(BodyStream::new(first.chain(rest)))
+            };
+            async move { Some(body) }
+        }));
+        let (body_one, big_one) = {
+            let mock = MockServer::new().with_mock(Gateway::new_replay(
+                "",
+                &format!("http://{}", upstream.address),
+                file_path,
+            ));
+            let mut res = reqwest::get(&mock.url("stream")).await.expect("Valid get");
+            let first = timeout(Duration::from_secs(5), res.chunk())
+                .await
+                .expect("The first chunk before the upstream is done")
+                .expect("chunk");
+            assert_eq!(first, Some(Bytes::from("streamed ")));
+            first_arrived.notify_one();
+            let body = format!("streamed {}", res.text().await.expect("text"));
+            let big = reqwest::get(&mock.url("big"))
+                .await
+                .expect("Valid get")
+                .bytes()
+                .await
+                .expect("bytes");
+            mock.filter_remove_mock(|_| false);
+            (body, big)
+        };
+
+        assert_eq!(body_one, "streamed body");
+        assert_eq!(big_one.to_vec(), big);
+
+        // Bodies without a length and big ones are both streamed, and recorded as chunks
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        match replays[0].then.body() {
+            DynamicBody::Chunks(chunks) => {
+                let data = chunks
+                    .iter()
+                    .flat_map(|x| x.data.clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(data, b"streamed body");
+            }
+            then => panic!("Expected chunks, got {:?}", then),
+        }
+        assert!(matches!(replays[1].then.body(), DynamicBody::Chunks(_)));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let body_two = reqwest::get(&mock.url("stream"))
+            .await
+            .expect("Valid get")
+            .text()
+            .await
+            .expect("text");
+        let big_two = reqwest::get(&mock.url("big"))
+            .await
+            .expect("Valid get")
+            .bytes()
+            .await
+            .expect("bytes");
+
+        assert_eq!(body_one, body_two);
+        assert_eq!(big_two.to_vec(), big);
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn sse_mock_test() {
+        let mock = MockServer::new().with_mock(SseMock::new(
+            "/events",
+            vec![
+                SseEvent::new("hello").with_event("greeting").with_id("1"),
+                SseEvent::new("two\nlines").with_delay(20),
+            ],
+        ));
+        let res = reqwest::get(&mock.url("events")).await.expect("Valid get");
+
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        assert_eq!(
+            res.text().await.expect("text"),
+            "id: 1\nevent: greeting\ndata: hello\n\ndata: two\ndata: lines\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn gateway_records_sse_and_replays_accelerated() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-sse.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream = MockServer::new().with_mock(SseMock::new(
+            "/events",
+            vec![
+                SseEvent::new("one"),
+                SseEvent::new("two").with_delay(200),
+                SseEvent::new("three").with_event("done").with_delay(200),
+            ],
+        ));
+        let body_one = {
+            let mock = MockServer::new().with_mock(Gateway::new_replay(
+                "",
+                &format!("http://{}", upstream.address),
+                file_path,
+            ));
+            let res = reqwest::get(&mock.url("events")).await.expect("Valid get");
+            assert_eq!(res.headers()["content-type"], "text/event-stream");
+            let body = res.text().await.expect("text");
+            mock.filter_remove_mock(|_| false);
+            body
+        };
+
+        let replays: Vec<Replay> =
+            serde_json::from_reader(std::fs::File::open(file_path).unwrap()).expect("replays");
+        match replays[0].then.body() {
+            DynamicBody::Events(events) => {
+                assert_eq!(events.len(), 3);
+                assert!(events[1].delay_ms >= 150);
+                assert_eq!(events[2].event.as_deref(), Some("done"));
+            }
+            other => panic!("Expected events, got {:?}", other),
+        }
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path).with_speed(10.0));
+        let start = Instant::now();
+        let body_two = reqwest::get(&mock.url("events"))
+            .await
+            .expect("Valid get")
+            .text()
+            .await
+            .expect("text");
+
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(body_one, body_two);
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn websocket_script_test() {
+        let mock = MockServer::new().with_mock(WebSocketMock::new(
+            "/chat",
+            vec![
+                WsStep::expect(json!({"type": "hello"})),
+                WsStep::send("welcome"),
+                WsStep::send_after(20, json!({"type": "push"})),
+            ],
+        ));
+        let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+            .await
+            .expect("Valid websocket");
+
+        socket
+            .send(Message::Text(
+                r#"{"type":"hello","name":"test"}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text("welcome".to_string())
+        );
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text(r#"{"type":"push"}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_gateway_records_session() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-ws.json");
+        let file_path = file_path.to_str().unwrap();
+        let _ = remove_file(file_path);
+        let upstream = MockServer::new().with_mock(WebSocketMock::new(
+            "/chat",
+            vec![WsStep::expect("ping"), WsStep::send_after(50, "pong")],
+        ));
+        let mock = MockServer::new().with_mock(WebSocketGateway::new_replay(
+            "",
+            &format!("ws://{}", upstream.address),
+            file_path,
+        ));
+        {
+            let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+                .await
+                .expect("Valid websocket");
+            socket
+                .send(Message::Text("ping".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(
+                socket.next().await.unwrap().unwrap(),
+                Message::Text("pong".to_string())
+            );
+            socket.close(None).await.unwrap();
+        }
+        mock.filter_remove_mock(|_| false);
+        timeout(Duration::from_secs(1), async {
+            while !std::path::Path::new(file_path).exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Recording written");
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+            .await
+            .expect("Valid websocket");
+        socket
+            .send(Message::Text("ping".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text("pong".to_string())
+        );
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn websocket_mocks_only_answer_upgrades() {
+        let mock = MockServer::new()
+            .with_mock(WebSocketGateway::new("", "ws://127.0.0.1:9"))
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("plain")) }));
+        let body: Value = reqwest::get(mock.url("facts"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("plain"));
+
+        let mock = MockServer::new().with_mock(WebSocketMock::new("/chat", vec![]));
+        let res = reqwest::get(mock.url("chat")).await.expect("Valid get");
+        assert!(!res.status().is_success());
+        assert!(connect_async(format!("ws://{}/missing", mock.address))
+            .await
+            .is_err());
+        assert_eq!(mock.journal().len(), 2);
+
+        // A recorded session replays to upgrades, plain requests go on to the next mock
+        let mock = MockServer::new()
+            .with_mock(ReplayMock::new(vec![Replay {
+                when: Request {
+                    path: "/chat".to_string(),
+                    queries: None,
+                    method: Method::Get,
+                    headers: vec![],
+                    body: None,
+                },
+                then: DynamicBody::WebSocket(vec![WsStep::send(json!("hi"))]),
+                version: None,
+            }]))
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("plain")) }));
+        let body: Value = reqwest::get(mock.url("chat"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("plain"));
+        let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+            .await
+            .expect("Connect");
+        let message = socket
+            .next()
+            .await
+            .expect("Message")
+            .expect("Valid message");
+        assert_eq!(message, Message::text(json!("hi").to_string()));
+    }
+
+    #[tokio::test]
+    async fn https_test() {
+        let mock = MockServer::new_https()
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("Secure")) }));
+        let url = mock.url("facts");
+        assert!(url.starts_with("https://"));
+
+        let untrusted = reqwest::Client::new().get(&url).send().await;
+        assert!(untrusted.is_err());
+
+        let ca = reqwest::Certificate::from_pem(mock.tls.as_ref().unwrap().ca_pem.as_bytes())
+            .expect("Valid CA");
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap();
+        let body: Value = client
+            .get(&url)
+            .send()
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+
+        assert_eq!(body, json!("Secure"));
+    }
+
+    #[tokio::test]
+    async fn https_shared_ca_test() {
+        let certificates = TlsCertificates::generate();
+        let ca = reqwest::Certificate::from_pem(certificates.ca_pem.as_bytes()).unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let mock = MockServer::new_https_with(certificates.clone())
+                .with_mock(ClosureMock::new(|_req| async { Some("ok") }));
+            let url = format!("https://localhost:{}/", mock.address.port());
+            let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn http2_test() {
+        let mock =
+            MockServer::new_https().with_mock(ClosureMock::new(|_req| async { Some(json!("h2")) }));
+        let ca = reqwest::Certificate::from_pem(mock.tls.as_ref().unwrap().ca_pem.as_bytes())
+            .expect("Valid CA");
+        let client = reqwest::Client::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap();
+        let res = client
+            .get(mock.url("alpn"))
+            .send()
+            .await
+            .expect("Valid get");
+        assert_eq!(res.version(), reqwest::Version::HTTP_2);
+
+        let mock =
+            MockServer::new().with_mock(ClosureMock::new(|_req| async { Some(json!("h2c")) }));
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let res = client.get(mock.url("h2c")).send().await.expect("Valid get");
+        assert_eq!(res.version(), reqwest::Version::HTTP_2);
+        assert_eq!(res.json::<Value>().await.unwrap(), json!("h2c"));
+    }
+
+    #[tokio::test]
+    async fn gateway_records_http2_version() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-h2.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream =
+            MockServer::new().with_mock(ClosureMock::new(|_req| async { Some(json!({"a": 1})) }));
+        let mock = MockServer::new().with_mock(
+            Gateway::builder("", &format!("http://{}", upstream.address))
+                .replay_file(file_path)
+                .http2_prior_knowledge()
+                .build(),
+        );
+        let body: Value = reqwest::get(&mock.url("h2"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!({"a": 1}));
+        mock.filter_remove_mock(|_| false);
+
+        let replays: Vec<Replay> =
+            serde_json::from_reader(std::fs::File::open(file_path).unwrap()).expect("replays");
+        assert_eq!(replays[0].version, Some(HttpVersion::Http2));
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    fn greeter_descriptors() -> GrpcDescriptors {
+        use prost::Message as _;
+        use prost_types::{
+            field_descriptor_proto::{Label, Type},
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+            MethodDescriptorProto, ServiceDescriptorProto,
+        };
+        let string_message = |name: &str, field: &str| DescriptorProto {
+            name: Some(name.to_string()),
+            field: vec![FieldDescriptorProto {
+                name: Some(field.to_string()),
+                json_name: Some(field.to_string()),
+                number: Some(1),
+                label: Some(Label::Optional as i32),
+                r#type: Some(Type::String as i32),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let method =
+            |name: &str, client_streaming: bool, server_streaming: bool| MethodDescriptorProto {
+                name: Some(name.to_string()),
+                input_type: Some(".test.HelloRequest".to_string()),
+                output_type: Some(".test.HelloReply".to_string()),
+                client_streaming: Some(client_streaming),
+                server_streaming: Some(server_streaming),
+                ..Default::default()
+            };
+        let file = FileDescriptorProto {
+            name: Some("greeter.proto".to_string()),
+            package: Some("test".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![
+                string_message("HelloRequest", "name"),
+                string_message("HelloReply", "message"),
+            ],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Greeter".to_string()),
+                method: vec![
+                    method("SayHello", false, false),
+                    method("SayHellos", false, true),
+                    method("Chat", true, true),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        GrpcDescriptors::from_bytes(&FileDescriptorSet { file: vec![file] }.encode_to_vec())
+    }
+
+    fn greeter_replay(path: &str, name: &str, then: GrpcResponse) -> Replay {
+        Replay {
+            when: Request {
+                path: path.to_string(),
+                queries: None,
+                method: Method::Post,
+                headers: vec![],
+                body: Some(DynamicBody::Json(json!({ "name": name }))),
+            },
+            then: DynamicBody::Grpc(then),
+            version: None,
+        }
+    }
+
+    /// A protobuf message with a single string in field 1
+    fn proto_string(value: &str) -> Vec<u8> {
+        let mut message = vec![0x0a, value.len() as u8];
+        message.extend_from_slice(value.as_bytes());
+        message
+    }
+
+    async fn grpc_call(url: &str, message: &[u8]) -> (Vec<Vec<u8>>, warp::http::HeaderMap) {
+        use warp::hyper::{body::HttpBody, Body, Client};
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let request = warp::http::Request::post(url)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(encode_grpc_frame(message)))
+            .unwrap();
+        let mut response = client.request(request).await.expect("Valid call");
+        assert_eq!(response.headers()["content-type"], "application/grpc");
+        let mut body = vec![];
+        while let Some(chunk) = response.body_mut().data().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = response.body_mut().trailers().await.unwrap().unwrap();
+        (decode_grpc_frames(&body).unwrap(), trailers)
+    }
+
+    #[tokio::test]
+    async fn grpc_mock_test() {
+        let mock = MockServer::new().with_mock(GrpcMock::new(
+            greeter_descriptors(),
+            vec![
+                greeter_replay(
+                    "/test.Greeter/SayHello",
+                    "world",
+                    GrpcResponse::ok(vec![json!({"message": "Hello world"})]),
+                ),
+                greeter_replay(
+                    "/test.Greeter/SayHello",
+                    "bad",
+                    GrpcResponse::error(3, "bad name").with_trailer("x-reason", "test"),
+                ),
+                greeter_replay(
+                    "/test.Greeter/SayHellos",
+                    "many",
+                    GrpcResponse::ok(vec![json!({"message": "one"}), json!({"message": "two"})]),
+                ),
+            ],
+        ));
+
+        let (messages, trailers) =
+            grpc_call(&mock.url("test.Greeter/SayHello"), &proto_string("world")).await;
+        assert_eq!(messages, vec![proto_string("Hello world")]);
+        assert_eq!(trailers["grpc-status"], "0");
+
+        let (messages, trailers) =
+            grpc_call(&mock.url("test.Greeter/SayHello"), &proto_string("bad")).await;
+        assert!(messages.is_empty());
+        assert_eq!(trailers["grpc-status"], "3");
+        assert_eq!(trailers["grpc-message"], "bad name");
+        assert_eq!(trailers["x-reason"], "test");
+
+        let (messages, _) =
+            grpc_call(&mock.url("test.Greeter/SayHellos"), &proto_string("many")).await;
+        assert_eq!(messages, vec![proto_string("one"), proto_string("two")]);
+    }
+
+    #[tokio::test]
+    async fn grpc_gateway_records_calls() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-grpc.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream = MockServer::new().with_mock(GrpcMock::new(
+            greeter_descriptors(),
+            vec![greeter_replay(
+                "/test.Greeter/SayHellos",
+                "many",
+                GrpcResponse::ok(vec![json!({"message": "one"}), json!({"message": "two"})])
+                    .with_trailer("x-reason", "recorded"),
+            )],
+        ));
+        let mock = MockServer::new().with_mock(GrpcGateway::new_replay(
+            "",
+            &format!("http://{}", upstream.address),
+            greeter_descriptors(),
+            file_path,
+        ));
+        let (messages, trailers) =
+            grpc_call(&mock.url("test.Greeter/SayHellos"), &proto_string("many")).await;
+        assert_eq!(messages, vec![proto_string("one"), proto_string("two")]);
+        assert_eq!(trailers["x-reason"], "recorded");
+        mock.filter_remove_mock(|_| false);
+
+        let replays: Vec<Replay> =
+            serde_json::from_reader(std::fs::File::open(file_path).unwrap()).expect("replays");
+        assert_eq!(
+            replays[0].when.body,
+            Some(DynamicBody::Json(json!({"name": "many"})))
+        );
+
+        let mock =
+            MockServer::new().with_mock(GrpcMock::from_file(greeter_descriptors(), file_path));
+        let (messages, trailers) =
+            grpc_call(&mock.url("test.Greeter/SayHellos"), &proto_string("many")).await;
+        assert_eq!(messages, vec![proto_string("one"), proto_string("two")]);
+        assert_eq!(trailers["x-reason"], "recorded");
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn grpc_gateway_under_a_path() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-grpc-path.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream = MockServer::new().with_mock(GrpcMock::new(
+            greeter_descriptors(),
+            vec![greeter_replay(
+                "/test.Greeter/SayHello",
+                "world",
+                GrpcResponse::ok(vec![json!({"message": "Hello world"})]),
+            )],
+        ));
+        let mock = MockServer::new().with_mock(GrpcGateway::new_replay(
+            "/grpc",
+            &format!("http://{}", upstream.address),
+            greeter_descriptors(),
+            file_path,
+        ));
+        let (messages, _) = grpc_call(
+            &mock.url("grpc/test.Greeter/SayHello"),
+            &proto_string("world"),
+        )
+        .await;
+        assert_eq!(messages, vec![proto_string("Hello world")]);
+        mock.filter_remove_mock(|_| false);
+
+        let replays: Vec<Replay> =
+            serde_json::from_reader(std::fs::File::open(file_path).unwrap()).expect("replays");
+        assert_eq!(replays[0].when.path, "/test.Greeter/SayHello");
+
+        let mock = MockServer::new()
+            .with_mock(GrpcMock::from_file(greeter_descriptors(), file_path).with_path("/grpc"));
+        let (messages, _) = grpc_call(
+            &mock.url("grpc/test.Greeter/SayHello"),
+            &proto_string("world"),
+        )
+        .await;
+        assert_eq!(messages, vec![proto_string("Hello world")]);
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn grpc_gateway_streams_both_ways() {
+        use warp::hyper::{body::HttpBody, Body, Client};
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-grpc-chat.json");
+        let file_path = file_path.to_str().unwrap();
+        fn bytes_of<B: bytes::Buf + Send + 'static>(
+            body: impl futures_util::Stream<Item = Result<B, warp::Error>> + Send + 'static,
+        ) -> impl futures_util::Stream<Item = Bytes> + Send + 'static {
+            body.filter_map(|chunk| async move {
+                let mut chunk = chunk.ok()?;
+                Some(chunk.copy_to_bytes(chunk.remaining()))
+            })
+        }
+        // Answers each message as soon as it arrives, and sends the caller's user back
+        let upstream = warp::header::<String>("x-user")
+            .and(warp::body::stream())
+            .map(|user: String, body| {
+                let (mut sender, reply) = Body::channel();
+                tokio::spawn(async move {
+                    let mut body = Box::pin(bytes_of(body));
+                    let mut buffer = vec![];
+                    while let Some(chunk) = body.next().await {
+                        buffer.extend_from_slice(&chunk);
+                        while let Some(messages) = decode_grpc_frames(&buffer) {
+                            if messages.is_empty() {
+                                break;
+                            }
+                            for message in messages {
+                                let name = String::from_utf8_lossy(&message[2..]).to_string();
+                                let reply = proto_string(&format!("{}!", name));
+                                sender
+                                    .send_data(encode_grpc_frame(&reply).into())
+                                    .await
+                                    .unwrap();
+                            }
+                            buffer.clear();
+                        }
+                    }
+                    let mut trailers = warp::http::HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    trailers.insert("x-user", user.parse().unwrap());
+                    sender.send_trailers(trailers).await.unwrap();
+                });
+                warp::http::Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(reply)
+                    .unwrap()
+            });
+        let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let mock = MockServer::new().with_mock(GrpcGateway::new_replay(
+            "",
+            &format!("http://{}", upstream),
+            greeter_descriptors(),
+            file_path,
+        ));
+
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let (mut sender, body) = Body::channel();
+        let request = warp::http::Request::post(mock.url("test.Greeter/Chat"))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("x-user", "ada")
+            .body(body)
+            .unwrap();
+        sender
+            .send_data(encode_grpc_frame(&proto_string("one")).into())
+            .await
+            .unwrap();
+        let mut response = client.request(request).await.expect("Valid call");
+        // The first answer comes back while the call is still open
+        let first = timeout(Duration::from_secs(5), response.body_mut().data())
+            .await
+            .expect("Streamed answer")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            decode_grpc_frames(&first).unwrap(),
+            vec![proto_string("one!")]
+        );
+        sender
+            .send_data(encode_grpc_frame(&proto_string("two")).into())
+            .await
+            .unwrap();
+        drop(sender);
+        let mut rest = vec![];
+        while let Some(chunk) = response.body_mut().data().await {
+            rest.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(
+            decode_grpc_frames(&rest).unwrap(),
+            vec![proto_string("two!")]
+        );
+        let trailers = response.body_mut().trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-user"], "ada");
+        mock.filter_remove_mock(|_| false);
+
+        let replays: Vec<Replay> =
+            serde_json::from_reader(std::fs::File::open(file_path).unwrap()).expect("replays");
+        assert_eq!(
+            replays[0].when.body,
+            Some(DynamicBody::Json(json!([{"name": "one"}, {"name": "two"}])))
+        );
+        assert_eq!(
+            replays[0].then,
+            DynamicBody::Grpc(
+                GrpcResponse::ok(vec![json!({"message": "one!"}), json!({"message": "two!"})])
+                    .with_trailer("x-user", "ada")
+            )
+        );
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn graphql_replay_matches_normalized_query() {
+        let replay: Replay = serde_json::from_value(json!({
+            "when": {
+                "path": "/graphql",
+                "queries": null,
+                "method": "Post",
+                "body": {"Json": {
+                    "query": "query GetUser($id: ID!) { user(id: $id) { name } }",
+                    "variables": {"id": "1"}
+                }}
+            },
+            "then": {"Json": {"data": {"user": {"name": "Ada"}}}}
+        }))
+        .unwrap();
+        let mock = MockServer::new().with_mock(ReplayMock::new(vec![replay]));
+        let client = reqwest::Client::new();
+
+        let body: Value = client
+            .post(mock.url("graphql"))
+            .json(&json!({
+                "operationName": "GetUser",
+                "query": "# a comment\nquery GetUser($id: ID!) {\n  user(id: $id) {\n    name\n  }\n}",
+                "variables": {"id": "1", "extra": true}
+            }))
+            .send()
+            .await
+            .expect("Valid post")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!({"data": {"user": {"name": "Ada"}}}));
+
+        let res = client
+            .post(mock.url("graphql"))
+            .json(&json!({
+                "query": "query GetUser($id: ID!) { user(id: $id) { name email } }",
+                "variables": {"id": "1"}
+            }))
+            .send()
+            .await
+            .expect("Valid post");
+        assert!(!res.status().is_success());
+    }
+
+    #[test]
+    fn query_field_bodies_match_whole_body() {
+        let replay = |body: Value| Replay {
+            when: Request {
+                path: "/search".to_string(),
+                queries: None,
+                method: Method::Post,
+                headers: vec![],
+                body: Some(DynamicBody::Json(body)),
+            },
+            then: DynamicBody::Json(json!("found")),
+            version: None,
+        };
+        let search = replay(json!({"query": "cats", "page": 1}));
+        let graphql = replay(json!({"query": "{ cats { name } }", "extensions": {"v": 1}}));
+        let search_with = |page| Request {
+            body: Some(DynamicBody::Json(json!({"query": "cats", "page": page}))),
+            ..search.when.clone()
+        };
+        assert!(search.matches_request(&search_with(1)));
+        assert!(!search.matches_request(&search_with(2)));
+
+        let graphql_with = |version| Request {
+            body: Some(DynamicBody::Json(
+                json!({"query": "{cats {name}}", "extensions": {"v": version}}),
+            )),
+            ..graphql.when.clone()
+        };
+        assert!(graphql.matches_request(&graphql_with(1)));
+        assert!(!graphql.matches_request(&graphql_with(2)));
+    }
+
+    #[tokio::test]
+    async fn graphql_mock_test() {
+        let mock = MockServer::new().with_mock(
+            GraphQlMock::new("/graphql")
+                .with_operation_variables("GetUser", json!({"id": "2"}), json!({"data": "two"}))
+                .with_operation("GetUser", json!({"data": "any"}))
+                .with_operation("Other", json!({"data": "other"})),
+        );
+        let client = reqwest::Client::new();
+        let call = |query: &'static str, variables: Value| {
+            let request = client
+                .post(mock.url("graphql"))
+                .json(&json!({"query": query, "variables": variables}));
+            async move {
+                request
+                    .send()
+                    .await
+                    .expect("Valid post")
+                    .json::<Value>()
+                    .await
+                    .expect("Serde")
+            }
+        };
+
+        assert_eq!(
+            call("query GetUser { user { name } }", json!({"id": "2"})).await,
+            json!({"data": "two"})
+        );
+        assert_eq!(
+            call("query GetUser { user { name } }", json!({"id": "3"})).await,
+            json!({"data": "any"})
+        );
+        assert_eq!(
+            call("query Other { thing }", json!(null)).await,
+            json!({"data": "other"})
+        );
+    }
+
+    #[tokio::test]
+    async fn gateway_redacts_secrets() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-redact.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream = MockServer::new().with_mock(ClosureMock::new(|_req| async {
+            Some(json!({"user": "ada", "session": "token-abc123"}))
+        }));
+        let client = reqwest::Client::new();
+        let login = |api_key: &str, password: &str, url: String| {
+            client
+                .post(format!("{}?user=ada&api_key={}", url, api_key))
+                .json(&json!({"user": "ada", "password": password}))
+                .send()
+        };
+        {
+            let mock = MockServer::new().with_mock(
+                Gateway::new_replay("", &format!("http://{}", upstream.address), file_path)
+                    .with_redaction(Redaction::query_param("api_key"))
+                    .with_redaction(Redaction::json_pointer("/password"))
+                    .with_redaction(Redaction::regex("token-[a-z0-9]+")),
+            );
+            let body: Value = login("secret1", "hunter2", mock.url("login"))
+                .await
+                .expect("Valid post")
+                .json()
+                .await
+                .expect("Serde");
+            assert_eq!(body, json!({"user": "ada", "session": "token-abc123"}));
+            mock.filter_remove_mock(|_| false);
+        }
+
+        let cassette = std::fs::read_to_string(file_path).unwrap();
+        assert!(!cassette.contains("secret1"));
+        assert!(!cassette.contains("hunter2"));
+        assert!(!cassette.contains("token-abc123"));
+        assert!(cassette.contains(REDACTED));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let body: Value = login("other-key", "other-password", mock.url("login"))
+            .await
+            .expect("Valid post")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!({"user": "ada", "session": REDACTED}));
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[test]
+    fn redaction_scrubs_every_body() {
+        let redaction = Redaction::regex("token-[a-z0-9]+");
+        let request = |body: Vec<u8>| Request {
+            path: "/upload".to_string(),
+            queries: None,
+            method: Method::Post,
+            headers: vec![],
+            body: Some(DynamicBody::Bytes(body)),
+        };
+        let redacted = |then: DynamicBody| {
+            let mut replay = Replay {
+                when: request(b"\xff\x00token-abc123\xfe".to_vec()),
+                then,
+                version: None,
+            };
+            redaction.redact(&mut replay);
+            replay
+        };
+
+        let replay = redacted(DynamicBody::Chunks(vec![
+            Chunk::new(0, "first token-abc123"),
+            Chunk::new(10, b"\xfftoken-def456".to_vec()),
+        ]));
+        let mut binary = b"\xff\x00".to_vec();
+        binary.extend_from_slice(REDACTED.as_bytes());
+        binary.push(0xfe);
+        assert_eq!(replay.when.body, Some(DynamicBody::Bytes(binary)));
+        let mut second = b"\xff".to_vec();
+        second.extend_from_slice(REDACTED.as_bytes());
+        assert_eq!(
+            replay.then,
+            DynamicBody::Chunks(vec![
+                Chunk::new(0, format!("first {}", REDACTED)),
+                Chunk::new(10, second),
+            ])
+        );
+        assert!(replay.matches_request(&request(b"\xff\x00token-other\xfe".to_vec())));
+        assert!(!replay.matches_request(&request(b"\xff\x01token-other\xfe".to_vec())));
+
+        let replay = redacted(DynamicBody::Events(vec![SseEvent::new("token-abc123")]));
+        assert_eq!(
+            replay.then,
+            DynamicBody::Events(vec![SseEvent::new(REDACTED)])
+        );
+
+        let replay = redacted(DynamicBody::WebSocket(vec![
+            WsStep::expect(json!({"auth": "token-abc123"})),
+            WsStep::send_after(0, "welcome token-abc123"),
+            WsStep::send_after(0, b"\xfftoken-abc123".to_vec()),
+        ]));
+        let mut binary = b"\xff".to_vec();
+        binary.extend_from_slice(REDACTED.as_bytes());
+        assert_eq!(
+            replay.then,
+            DynamicBody::WebSocket(vec![
+                WsStep::expect(json!({ "auth": REDACTED })),
+                WsStep::send_after(0, format!("welcome {}", REDACTED)),
+                WsStep::send_after(0, binary),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_gateway_redacts_messages() {
+        let file_path = std::env::temp_dir().join("replay-mocker-ws-redact.json");
+        let file_path = file_path.to_str().unwrap();
+        let _ = remove_file(file_path);
+        let upstream = MockServer::new().with_mock(WebSocketMock::new(
+            "/chat",
+            vec![
+                WsStep::expect("login token-secret1"),
+                WsStep::send_after(0, "welcome token-abc123"),
+            ],
+        ));
+        let mock = MockServer::new().with_mock(
+            WebSocketGateway::new_replay("", &format!("ws://{}", upstream.address), file_path)
+                .with_redaction(Redaction::regex("token-[a-z0-9]+")),
+        );
+        {
+            let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+                .await
+                .expect("Valid websocket");
+            socket
+                .send(Message::Text("login token-secret1".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(
+                socket.next().await.unwrap().unwrap(),
+                Message::Text("welcome token-abc123".to_string())
+            );
+            socket.close(None).await.unwrap();
+        }
+        mock.filter_remove_mock(|_| false);
+        timeout(Duration::from_secs(1), async {
+            while !std::path::Path::new(file_path).exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Recording written");
+
+        let cassette = std::fs::read_to_string(file_path).unwrap();
+        assert!(!cassette.contains("token-secret1"));
+        assert!(!cassette.contains("token-abc123"));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+            .await
+            .expect("Valid websocket");
+        socket
+            .send(Message::Text("login token-other".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text(format!("welcome {}", REDACTED))
+        );
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn gateway_hooks_rewrite_requests_and_responses() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-hooks.json");
+        let file_path = file_path.to_str().unwrap();
+        let echo = warp::path::full()
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::json())
+            .map(
+                |path: warp::path::FullPath, auth: Option<String>, body: Value| {
+                    warp::reply::json(&json!({"path": path.as_str(), "auth": auth, "body": body}))
+                },
+            );
+        let (upstream, server) = warp::serve(echo).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        {
+            let mock = MockServer::new().with_mock(
+                Gateway::new_replay("/api", "http://wrong.invalid", file_path)
+                    .with_header("authorization", "Bearer upstream-token")
+                    .with_request_hook(move |request| {
+                        request.uri = format!("http://{}", upstream);
+                        request.path = request.path.replacen("/v1/", "/v2/", 1);
+                        if let Some(DynamicBody::Json(body)) = &mut request.body {
+                            body["source"] = json!("gateway");
+                        }
+                    })
+                    .with_response_hook(|response| {
+                        if let DynamicBody::Json(body) = response {
+                            body["auth"] = json!(null);
+                        }
+                    }),
+            );
+            let body: Value = reqwest::Client::new()
+                .post(mock.url("api/v1/users"))
+                .json(&json!({"name": "ada"}))
+                .send()
+                .await
+                .expect("Valid post")
+                .json()
+                .await
+                .expect("Serde");
+            assert_eq!(
+                body,
+                json!({"path": "/v2/users", "auth": null, "body": {"name": "ada", "source": "gateway"}})
+            );
+            mock.filter_remove_mock(|_| false);
+        }
+
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        assert_eq!(replays[0].when.path, "/api/v1/users");
+        assert_eq!(
+            replays[0].when.body,
+            Some(DynamicBody::Json(json!({"name": "ada"})))
+        );
+        assert_eq!(
+            replays[0].then.body(),
+            &DynamicBody::Json(
+                json!({"path": "/v2/users", "auth": null, "body": {"name": "ada", "source": "gateway"}})
+            )
+        );
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn gateway_forwards_and_records_headers() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-headers.json");
+        let file_path = file_path.to_str().unwrap();
+        let echo = warp::header::optional::<String>("x-trace")
+            .and(warp::header::optional::<String>("authorization"))
+            .map(|trace: Option<String>, auth: Option<String>| {
+                warp::hyper::Response::builder()
+                    .header("content-type", "application/json")
+                    .header("set-cookie", "session=abc")
+                    .header("set-cookie", "theme=dark")
+                    .header("link", "</users?page=2>; rel=\"next\"")
+                    .body(json!({"trace": trace, "authorized": auth.is_some()}).to_string())
+            });
+        let (upstream, server) = warp::serve(echo).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let client = reqwest::Client::new();
+        {
+            let mock = MockServer::new().with_mock(
+                Gateway::new_replay("", &format!("http://{}", upstream), file_path)
+                    .with_redaction(Redaction::header("authorization")),
+            );
+            let res = client
+                .get(mock.url("users"))
+                .header("x-trace", "trace-1")
+                .header("authorization", "Bearer secret")
+                .send()
+                .await
+                .expect("Valid get");
+            let cookies: Vec<_> = res.headers().get_all("set-cookie").iter().collect();
+            assert_eq!(cookies, vec!["session=abc", "theme=dark"]);
+            assert_eq!(res.headers()["link"], "</users?page=2>; rel=\"next\"");
+            let body: Value = res.json().await.expect("Serde");
+            assert_eq!(body, json!({"trace": "trace-1", "authorized": true}));
+            mock.filter_remove_mock(|_| false);
+        }
+
+        let cassette = std::fs::read_to_string(file_path).unwrap();
+        assert!(!cassette.contains("Bearer secret"));
+        assert!(cassette.contains("trace-1"));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let res = client
+            .get(mock.url("users"))
+            .send()
+            .await
+            .expect("Valid get");
+        assert_eq!(res.headers()["link"], "</users?page=2>; rel=\"next\"");
+        assert_eq!(res.headers().get_all("set-cookie").iter().count(), 2);
+        remove_file(file_path).expect("Remove the file for the testing");
+
+        // Left out, the headers are still passed on but not recorded
+        {
+            let mock = MockServer::new().with_mock(
+                Gateway::new_replay("", &format!("http://{}", upstream), file_path)
+                    .without_recorded_headers(),
+            );
+            let res = client
+                .get(mock.url("users"))
+                .header("x-trace", "trace-2")
+                .send()
+                .await
+                .expect("Valid get");
+            assert_eq!(res.headers().get_all("set-cookie").iter().count(), 2);
+            mock.filter_remove_mock(|_| false);
+        }
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        assert!(replays[0].when.headers.is_empty());
+        assert_eq!(
+            replays[0].then,
+            DynamicBody::Json(json!({"trace": "trace-2", "authorized": false}))
+        );
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn gateway_builder_trusts_https_upstreams() {
+        let upstream = MockServer::new_https()
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("Secure")) }));
+        let upstream_uri = format!("https://{}", upstream.address);
+        let get = |mock: MockServer| async move {
+            reqwest::get(mock.url("facts"))
+                .await
+                .expect("Valid get")
+                .status()
+        };
+
+        let untrusted = MockServer::new().with_mock(Gateway::new("", &upstream_uri));
+        assert!(!get(untrusted).await.is_success());
+
+        let trusted = MockServer::new().with_mock(
+            Gateway::builder("", &upstream_uri)
+                .root_certificate(&upstream.tls.as_ref().unwrap().ca_pem)
+                .timeout(Duration::from_secs(5))
+                .build(),
+        );
+        assert!(get(trusted).await.is_success());
+
+        let insecure =
+            MockServer::new().with_mock(Gateway::builder("", &upstream_uri).insecure().build());
+        assert!(get(insecure).await.is_success());
+    }
+
+    #[tokio::test]
+    async fn gateway_passes_redirects_on() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-redirect.json");
+        let file_path = file_path.to_str().unwrap();
+        let old =
+            warp::path("old").map(|| warp::redirect::found(warp::http::Uri::from_static("/new")));
+        let new = warp::path("new").map(|| warp::reply::json(&json!("moved")));
+        let (upstream, server) = warp::serve(old.or(new)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let upstream_uri = format!("http://{}", upstream);
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        // Followed by default, so the client gets where it leads
+        let mock = MockServer::new().with_mock(Gateway::new("", &upstream_uri));
+        let body: Value = client
+            .get(mock.url("old"))
+            .send()
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("moved"));
+
+        {
+            let mock = MockServer::new().with_mock(
+                Gateway::builder("", &upstream_uri)
+                    .redirect(reqwest::redirect::Policy::none())
+                    .replay_file(file_path)
+                    .build(),
+            );
+            let res = client.get(mock.url("old")).send().await.expect("Valid get");
+            assert_eq!(res.status(), 302);
+            assert_eq!(res.headers()["location"], "/new");
+            mock.filter_remove_mock(|_| false);
+        }
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let res = client.get(mock.url("old")).send().await.expect("Valid get");
+        assert_eq!(res.status(), 302);
+        assert_eq!(res.headers()["location"], "/new");
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn gateway_proxies_any_method() {
+        let file_path = std::env::temp_dir().join("replay-mocker-gateway-methods.json");
+        let file_path = file_path.to_str().unwrap();
+        // Answers preflights like a real server, with a 204 and no body
+        let upstream = MockServer::new().with_mock(ClosureMock::new(|req| async move {
+            Some(match req.method {
+                Method::Options => DynamicBody::Text(String::new())
+                    .with_headers(vec![(
+                        "access-control-allow-methods".to_string(),
+                        "GET, PURGE, PROPFIND".to_string(),
+                    )])
+                    .with_status(204),
+                method => DynamicBody::Json(json!(method.as_method_string())),
+            })
+        }));
+        let client = reqwest::Client::new();
+        let call = |method: &str, url: String| {
+            client
+                .request(
+                    warp::http::Method::from_bytes(method.as_bytes()).unwrap(),
+                    url,
+                )
+                .send()
+        };
+        {
+            let mock = MockServer::new().with_mock(Gateway::new_replay(
+                "",
+                &format!("http://{}", upstream.address),
+                file_path,
+            ));
+            let res = call("OPTIONS", mock.url("resource"))
+                .await
+                .expect("Valid call");
+            assert_eq!(res.status(), 204);
+            assert_eq!(
+                res.headers()["access-control-allow-methods"],
+                "GET, PURGE, PROPFIND"
+            );
+            for method in ["PURGE", "PROPFIND"] {
+                let body: Value = call(method, mock.url("resource"))
+                    .await
+                    .expect("Valid call")
+                    .json()
+                    .await
+                    .expect("Serde");
+                assert_eq!(body, json!(method));
+            }
+            mock.filter_remove_mock(|_| false);
+        }
+
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        assert_eq!(replays[0].when.method, Method::Options);
+        assert_eq!(replays[1].when.method, Method::Other("PURGE".to_string()));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_file(file_path));
+        let body: Value = call("PROPFIND", mock.url("resource"))
+            .await
+            .expect("Valid call")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("PROPFIND"));
+        let res = call("OPTIONS", mock.url("resource"))
+            .await
+            .expect("Valid call");
+        assert_eq!(res.status(), 204);
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn cors_test() {
+        let mock = MockServer::new()
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("Facts")) }))
+            .with_cors(
+                Cors::new()
+                    .allow_origin("http://app.test")
+                    .allow_credentials()
+                    .expose_header("link"),
+            );
+        let client = reqwest::Client::new();
+        let preflight = |origin: &str| {
+            client
+                .request(warp::http::Method::OPTIONS, mock.url("facts"))
+                .header("origin", origin)
+                .header("access-control-request-method", "PUT")
+                .header("access-control-request-headers", "authorization")
+                .send()
+        };
+
+        let res = preflight("http://app.test").await.expect("Valid options");
+        assert_eq!(res.status(), 204);
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "http://app.test"
+        );
+        assert_eq!(res.headers()["access-control-allow-methods"], "PUT");
+        assert_eq!(
+            res.headers()["access-control-allow-headers"],
+            "authorization"
+        );
+        assert_eq!(res.headers()["access-control-allow-credentials"], "true");
+
+        let res = preflight("http://evil.test").await.expect("Valid options");
+        assert_eq!(res.status(), 403);
+
+        let res = client
+            .get(mock.url("facts"))
+            .header("origin", "http://app.test")
+            .send()
+            .await
+            .expect("Valid get");
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "http://app.test"
+        );
+        assert_eq!(res.headers()["access-control-expose-headers"], "link");
+        assert_eq!(res.json::<Value>().await.expect("Serde"), json!("Facts"));
+
+        let res = client
+            .get(mock.url("facts"))
+            .header("origin", "http://evil.test")
+            .send()
+            .await
+            .expect("Valid get");
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[tokio::test]
+    async fn admin_api_answers_bad_requests_and_removes_mocks() {
+        let mock = MockServer::new()
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("catch all")) }));
+        let client = reqwest::Client::new();
+        let res = client
+            .post(mock.url("__admin/replays"))
+            .header("content-type", "application/json")
+            .body("[{ not json")
+            .send()
+            .await
+            .expect("Valid post");
+        assert_eq!(res.status(), 400);
+        let res = reqwest::get(mock.url("__admin/replays"))
+            .await
+            .expect("Valid get");
+        assert_eq!(res.status(), 405);
+        let res = reqwest::get(mock.url("__admin/missing"))
+            .await
+            .expect("Valid get");
+        assert_eq!(res.status(), 404);
+        // None of them reached the mocks
+        assert!(mock.journal().is_empty());
+
+        let added: Value = client
+            .post(mock.url("__admin/replays"))
+            .json(&json!({
+                "when": {"path": "/facts", "queries": null, "method": "Get", "body": null},
+                "then": {"Json": "fact"}
+            }))
+            .send()
+            .await
+            .expect("Valid post")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(mock.mocks().len(), 2);
+        let remove = || {
+            client
+                .delete(mock.url(&format!("__admin/mocks/{}", added["id"])))
+                .send()
+        };
+        assert_eq!(remove().await.expect("Valid delete").status(), 204);
+        assert_eq!(remove().await.expect("Valid delete").status(), 404);
+        assert_eq!(mock.mocks().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn admin_api_test() {
+        let mock = MockServer::new();
+        let client = reqwest::Client::new();
+        let res = client
+            .post(mock.url("__admin/replays?name=facts&tags=cats,fixtures"))
+            .json(&json!([{
+                "when": {"path": "/facts", "queries": null, "method": "Get", "body": null},
+                "then": {"Json": {"fact": "cats sleep a lot"}}
+            }]))
+            .send()
+            .await
+            .expect("Valid post");
+        assert_eq!(res.status(), 201);
+
+        let body: Value = reqwest::get(mock.url("facts"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!({"fact": "cats sleep a lot"}));
+        assert!(!reqwest::get(mock.url("dogs"))
+            .await
+            .expect("Valid get")
+            .status()
+            .is_success());
+
+        let mocks: Value = reqwest::get(mock.url("__admin/mocks"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(
+            mocks[0]["description"],
+            json!({"replay": {"count": 1, "routes": ["GET /facts"]}})
+        );
+        assert_eq!(mocks[0]["name"], json!("facts"));
+        assert_eq!(mocks[0]["tags"], json!(["cats", "fixtures"]));
+        assert_eq!(mocks[0]["hits"], json!(1));
+
+        let journal: Vec<JournalEntry> = reqwest::get(mock.url("__admin/requests"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal[0].request.path, "/facts");
+        assert!(journal[0].matched);
+        assert_eq!(journal[0].mock_name.as_deref(), Some("facts"));
+        assert!(!journal[1].matched);
+        assert_eq!(mock.journal(), journal);
+
+        let res = client
+            .post(mock.url("__admin/reset"))
+            .send()
+            .await
+            .expect("Valid post");
+        assert_eq!(res.status(), 204);
+        assert!(!reqwest::get(mock.url("facts"))
+            .await
+            .expect("Valid get")
+            .status()
+            .is_success());
+        assert_eq!(mock.journal().len(), 1);
+
+        // A request no mock answers still goes through the mocks once
+        let res = client
+            .post(mock.url("dogs?a=1"))
+            .json(&json!({"name": "rex"}))
+            .send()
+            .await
+            .expect("Valid post");
+        assert_eq!(res.status(), 404);
+        reqwest::get(mock.url("dogs?b=2")).await.expect("Valid get");
+        let journal: Vec<JournalEntry> = reqwest::get(mock.url("__admin/requests"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(journal.len(), 3);
+        assert_eq!(journal[1].request.method, Method::Post);
+        assert_eq!(journal[1].request.queries.as_deref(), Some("a=1"));
+        assert_eq!(
+            journal[1].request.body,
+            Some(DynamicBody::Json(json!({"name": "rex"})))
+        );
+        assert_eq!(journal[2].request.queries.as_deref(), Some("b=2"));
+        assert_eq!(journal[2].request.body, None);
+    }
+
+    #[tokio::test]
+    async fn mock_handles_test() {
+        let mock = Arc::new(MockServer::new());
+        let get = |mock: Arc<MockServer>| async move {
+            reqwest::get(mock.url("health"))
+                .await
+                .expect("Valid get")
+                .json::<Value>()
+                .await
+                .expect("Serde")
+        };
+        mock.add_mock_with_priority(
+            ClosureMock::new(|_req| async { Some(json!("fallback")) }),
+            -1,
+        );
+        let health = mock.add_mock(ClosureMock::new(|_req| async { Some(json!("healthy")) }));
+        assert_eq!(get(mock.clone()).await, json!("healthy"));
+
+        assert!(health.replace(ClosureMock::new(|_req| async { Some(json!("failing")) })));
+        assert_eq!(get(mock.clone()).await, json!("failing"));
+
+        let urgent = mock
+            .add_mock_with_priority(ClosureMock::new(|_req| async { Some(json!("urgent")) }), 5);
+        assert_eq!(get(mock.clone()).await, json!("urgent"));
+
+        assert!(urgent.remove());
+        assert!(!urgent.remove());
+        assert!(health.remove());
+        assert_eq!(get(mock.clone()).await, json!("fallback"));
+    }
+
+    #[tokio::test]
+    async fn named_mocks_test() {
+        let mock = MockServer::new();
+        let cats = mock.add_mock_with(
+            ClosureMock::new(
+                |req: Request| async move { (req.path == "/cats").then(|| json!("meow")) },
+            ),
+            MockOptions::new().name("cats").tag("animals"),
+        );
+        mock.add_mock_with(
+            SseMock::new("/events", vec![SseEvent::new("one")]),
+            MockOptions::new().name("events").tag("streams"),
+        );
+        for _ in 0..2 {
+            reqwest::get(mock.url("cats")).await.expect("Valid get");
+        }
+
+        assert_eq!(cats.hits(), 2);
+        let infos = mock.mocks();
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[1].name.as_deref(), Some("events"));
+        assert_eq!(infos[1].hits, 0);
+        assert_eq!(infos[1].description["sse"]["path"], json!("/events"));
+        assert_eq!(mock.mocks_tagged("animals")[0].id, cats.info().unwrap().id);
+        assert_eq!(mock.journal()[1].mock_name.as_deref(), Some("cats"));
+
+        assert_eq!(mock.remove_mocks_tagged("animals"), 1);
+        assert!(cats.info().is_none());
+        assert_eq!(mock.mocks().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn limited_mocks_test() {
+        let mock = MockServer::new();
+        mock.add_mock(ClosureMock::new(|_req| async { Some(json!("healthy")) }));
+        let failing = mock.add_mock_with(
+            ClosureMock::new(|_req| async {
+                Some(DynamicBody::from(json!("unavailable")).with_status(503))
+            }),
+            MockOptions::new().priority(1).once(),
+        );
+        mock.add_mock_with(
+            ClosureMock::new(|_req| async { Some(json!("retry")) }),
+            MockOptions::new().priority(1).times(2),
+        );
+
+        let mut statuses = vec![];
+        for _ in 0..4 {
+            let res = reqwest::get(mock.url("health")).await.expect("Valid get");
+            statuses.push((
+                res.status().as_u16(),
+                res.json::<Value>().await.expect("Serde"),
+            ));
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                (503, json!("unavailable")),
+                (200, json!("retry")),
+                (200, json!("retry")),
+                (200, json!("healthy")),
+            ]
+        );
+        assert_eq!(failing.hits(), 1);
+        assert_eq!(failing.info().unwrap().times, Some(1));
+    }
+
+    #[tokio::test]
+    async fn replay_watch_reloads_test() {
+        let (logs, _guard) = Logs::capture();
+        let dir = std::env::temp_dir().join("replay-mocker-watch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let write =
+            |file: &str, contents: String| std::fs::write(dir.join(file), contents).unwrap();
+        let cassette = |path: &str, then: Value| {
+            json!([{
+                "when": {"path": path, "queries": null, "method": "Get", "body": null},
+                "then": {"Json": then}
+            }])
+            .to_string()
+        };
+        write("facts.json", cassette("/facts", json!("v1")));
+        let mock = MockServer::new().with_mock(ReplayMock::watch_every(
+            dir.to_str().unwrap(),
+            Duration::from_millis(20),
+        ));
+        let get = |path: &'static str| {
+            let url = mock.url(path);
+            async move {
+                let res = reqwest::get(url).await.expect("Valid get");
+                if !res.status().is_success() {
+                    return None;
+                }
+                res.json::<Value>().await.ok()
+            }
+        };
+        assert_eq!(get("facts").await, Some(json!("v1")));
+
+        // The same length and mtime, so only the contents tell the edit apart
+        let facts = dir.join("facts.json");
+        let modified = std::fs::metadata(&facts).unwrap().modified().unwrap();
+        write("facts.json", cassette("/facts", json!("v2")));
+        std::fs::File::options()
+            .write(true)
+            .open(&facts)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        eventually(|| async { get("facts").await == Some(json!("v2")) }).await;
+
+        let failed_reloads = || logs.text().matches("Keeping the old replays").count();
+        let failed_before = failed_reloads();
+        write("facts.json", "[{ not json".to_string());
+        eventually(|| async { failed_reloads() > failed_before }).await;
+        assert_eq!(get("facts").await, Some(json!("v2")));
+
+        write("facts.json", cassette("/facts", json!("v3")));
+        write("more.json", cassette("/more", json!("added")));
+        eventually(|| async { get("more").await == Some(json!("added")) }).await;
+        assert_eq!(get("facts").await, Some(json!("v3")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cassettes_merge_test() {
+        let dir = std::env::temp_dir().join("replay-mocker-cassettes");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("users")).unwrap();
+        std::fs::create_dir_all(dir.join("billing")).unwrap();
+        let cassette = |replays: &[(&str, &str)]| {
+            Value::Array(
+                replays
+                    .iter()
+                    .map(|(path, then)| {
+                        json!({
+                            "when": {"path": path, "queries": null, "method": "Get", "body": null},
+                            "then": {"Json": then}
+                        })
+                    })
+                    .collect(),
+            )
+            .to_string()
+        };
+        let write =
+            |file: &str, contents: String| std::fs::write(dir.join(file), contents).unwrap();
+        write("users/list.json", cassette(&[("/users", "from users")]));
+        write("users/notes.txt", "not a cassette".to_string());
+        write(
+            "billing/invoices.json",
+            cassette(&[("/invoices", "invoices"), ("/users", "from billing")]),
+        );
+        let overrides = std::env::temp_dir().join("replay-mocker-cassettes-overrides.json");
+        std::fs::write(&overrides, cassette(&[("/invoices", "invoices")])).unwrap();
+
+        let cassettes = Cassettes::new()
+            .with_file(overrides.to_str().unwrap())
+            .with_dir(dir.to_str().unwrap(), "**/*.json");
+        assert_eq!(cassettes.replays().len(), 4);
+        let duplicates = cassettes.duplicates();
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].when.path, "/invoices");
+        assert!(!duplicates[0].conflicting);
+        assert_eq!(duplicates[1].when.path, "/users");
+        assert!(duplicates[1].conflicting);
+        assert!(duplicates[1].sources[0].ends_with("invoices.json"));
+
+        let mock = MockServer::new().with_mock(ReplayMock::from_cassettes(cassettes));
+        let body: Value = reqwest::get(mock.url("users"))
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("from billing"));
+
+        let only_users = Cassettes::new().with_dir(dir.to_str().unwrap(), "users/*.json");
+        assert_eq!(only_users.replays().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        remove_file(overrides).unwrap();
+    }
+
+    #[test]
+    fn cassette_duplicates_find_overlaps() {
+        let replay = |method: &str, path: &str, body: Value, then: &str| {
+            serde_json::from_value::<Replay>(json!({
+                "when": {"path": path, "queries": null, "method": method, "body": body},
+                "then": {"Json": then}
+            }))
+            .unwrap()
+        };
+        let dir = std::env::temp_dir().join("replay-mocker-overlaps");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let orders = vec![
+            replay(
+                "Get",
+                &format!("/orders/{}", REDACTED),
+                json!(null),
+                "any order",
+            ),
+            replay("Post", "/search", json!({"Json": {"q": "cats"}}), "cats"),
+        ];
+        std::fs::write(
+            dir.join("orders.json"),
+            serde_json::to_string(&orders).unwrap(),
+        )
+        .unwrap();
+        // A link back up the tree is skipped instead of walked forever
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+
+        let cassettes = Cassettes::new()
+            .with_dir(dir.to_str().unwrap(), "**/*.json")
+            .with_replays(
+                "later",
+                vec![
+                    replay("Get", "/orders/7", json!(null), "order 7"),
+                    replay(
+                        "Post",
+                        "/search",
+                        json!({"Json": {"q": "cats", "page": 2}}),
+                        "cats",
+                    ),
+                    replay("Post", "/search", json!({"Json": {"q": "dogs"}}), "dogs"),
+                ],
+            );
+        assert_eq!(cassettes.replays().len(), 5);
+        let duplicates = cassettes.duplicates();
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].when.path, format!("/orders/{}", REDACTED));
+        assert_eq!(duplicates[0].sources.len(), 2);
+        assert!(duplicates[0].sources[0].ends_with("orders.json"));
+        assert_eq!(duplicates[0].sources[1], "later");
+        assert!(duplicates[0].conflicting);
+        assert_eq!(duplicates[1].when.path, "/search");
+        assert_eq!(duplicates[1].sources.len(), 2);
+        assert!(!duplicates[1].conflicting);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn indexed_replays_test() {
+        let replay = |path: String, then: Value| Replay {
+            when: Request {
+                path,
+                queries: None,
+                method: Method::Get,
+                headers: vec![],
+                body: None,
+            },
+            then: DynamicBody::Json(then),
+            version: None,
+        };
+        let mut replays = (0..5000)
+            .map(|index| replay(format!("/items/{}", index), json!(index)))
+            .collect::<Vec<_>>();
+        replays.push(replay(format!("/users/{}", REDACTED), json!("any user")));
+        replays.push(replay("/users/me".to_string(), json!("me")));
+        replays.push(replay("/items/7".to_string(), json!("shadowed")));
+        let mock = MockServer::new().with_mock(ReplayMock::new(replays));
+        let get = |path: &str| {
+            let url = mock.url(path);
+            async move {
+                let res = reqwest::get(url).await.expect("Valid get");
+                if !res.status().is_success() {
+                    return None;
+                }
+                res.json::<Value>().await.ok()
+            }
+        };
+
+        assert_eq!(get("items/4999").await, Some(json!(4999)));
+        assert_eq!(get("items/7").await, Some(json!(7)));
+        assert_eq!(get("users/me").await, Some(json!("any user")));
+        assert_eq!(get("users/42").await, Some(json!("any user")));
+        assert_eq!(get("users/42/posts").await, None);
+    }
+
+    #[tokio::test]
+    async fn mock_changes_while_requests_run_test() {
+        let mock = MockServer::new();
+        // The slow mock says when a request reached it, then holds it until released
+        let (arrived, mut arrivals) = mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let held = release.clone();
+        let slow = mock.add_mock(ClosureMock::new(move |_req| {
+            let (arrived, held) = (arrived.clone(), held.clone());
+            async move {
+                arrived.send(()).unwrap();
+                held.acquire().await.unwrap().forget();
+                Some(json!("slow"))
+            }
+        }));
+        let url = mock.url("anything");
+        let in_flight = (0..8)
+            .map(|_| tokio::spawn(reqwest::get(url.clone())))
+            .collect::<Vec<_>>();
+        for _ in 0..8 {
+            arrivals.recv().await.unwrap();
+        }
+        assert!(slow.remove());
+        mock.add_mock(ClosureMock::new(|_req| async { Some(json!("fast")) }));
+        release.add_permits(8);
+
+        for request in in_flight {
+            let body: Value = request
+                .await
+                .expect("Request task")
+                .expect("Valid get")
+                .json()
+                .await
+                .expect("Serde");
+            assert_eq!(body, json!("slow"));
+        }
+        let body: Value = reqwest::get(url)
+            .await
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("fast"));
+        assert_eq!(mock.journal().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn limited_mock_runs_once_for_racing_requests() {
+        let mock = MockServer::new();
+        // The limited mock says when a request reached it, then holds it until released
+        let (arrived, mut arrivals) = mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let held = release.clone();
+        let once = mock.add_mock_with(
+            ClosureMock::new(move |_req| {
+                let (arrived, held) = (arrived.clone(), held.clone());
+                async move {
+                    arrived.send(()).unwrap();
+                    held.acquire().await.unwrap().forget();
+                    Some(json!("once"))
+                }
+            }),
+            MockOptions::new().once(),
+        );
+        mock.add_mock(ClosureMock::new(|_req| async { Some(json!("after")) }));
+        let get = |url: String| async move {
+            reqwest::get(url)
+                .await
+                .expect("Valid get")
+                .json::<Value>()
+                .await
+                .expect("Serde")
+        };
+        let first = tokio::spawn(get(mock.url("anything")));
+        arrivals.recv().await.unwrap();
+
+        // The hit is taken while the first request runs, so the second one goes on
+        let second = timeout(Duration::from_secs(5), get(mock.url("anything")))
+            .await
+            .expect("Not held by the used up mock");
+        assert_eq!(second, json!("after"));
+        release.add_permits(1);
+        assert_eq!(first.await.expect("Request task"), json!("once"));
+        assert!(arrivals.try_recv().is_err());
+        assert_eq!(once.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn journal_keeps_the_latest_requests() {
+        let mock = MockServer::new()
+            .with_journal_limit(2)
+            .with_mock(ClosureMock::new(|_req| async { Some(json!("ok")) }));
+        for path in ["one", "two", "three"] {
+            reqwest::get(mock.url(path)).await.expect("Valid get");
+        }
+        let paths = mock
+            .journal()
+            .into_iter()
+            .map(|entry| entry.request.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/two", "/three"]);
+
+        let mock = mock.with_journal_limit(0);
+        assert!(mock.journal().is_empty());
+        reqwest::get(mock.url("four")).await.expect("Valid get");
+        assert!(mock.journal().is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_tracing_test() {
+        let (logs, _guard) = Logs::capture();
+
+        let mock = MockServer::new();
+        mock.add_mock_with(
+            ClosureMock::new(|_req| async { None::<Value> }),
+            MockOptions::new().name("never"),
+        );
+        mock.add_mock_with(
+            ReplayMock::new(vec![Replay {
+                when: Request {
+                    path: "/facts".to_string(),
+                    queries: None,
+                    method: Method::Get,
+                    headers: vec![],
+                    body: None,
+                },
+                then: DynamicBody::Json(json!("cats sleep a lot")),
+                version: None,
+            }]),
+            MockOptions::new().name("facts"),
+        );
+        reqwest::get(mock.url("facts")).await.expect("Valid get");
+        reqwest::get(mock.url("dogs")).await.expect("Valid get");
+
+        let logs = logs.text();
+        let answered = logs
+            .lines()
+            .find(|line| line.contains("Mock answered"))
+            .expect("Answered log");
+        assert!(answered.contains("path=/facts"), "{}", answered);
+        assert!(answered.contains("mock_name=\"facts\""), "{}", answered);
+        assert!(answered.contains("latency_ms="), "{}", answered);
+        assert!(logs.contains("Mock didn't match mock_id=0 mock_name=\"never\""));
+        assert!(logs.contains("No replay for the method and path"));
+        assert!(logs.contains("No mock matched"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_requests_and_flushes_recordings() {
+        let file_path = std::env::temp_dir().join("replay-mocker-shutdown.json");
+        let file_path = file_path.to_str().unwrap();
+        let upstream = warp::path("facts").map(|| warp::reply::json(&json!("cats sleep a lot")));
+        let (upstream, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mock = MockServer::new();
+        let gateway = mock.add_mock(Gateway::new_replay(
+            "/api",
+            &format!("http://{}", upstream),
+            file_path,
+        ));
+        // The slow mock says when the request reached it, then holds it until released
+        let (arrived, mut arrivals) = mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let held = release.clone();
+        mock.add_mock(ClosureMock::new(move |_req| {
+            let (arrived, held) = (arrived.clone(), held.clone());
+            async move {
+                arrived.send(()).unwrap();
+                held.acquire().await.unwrap().forget();
+                Some(json!("slow"))
+            }
+        }));
+        reqwest::get(mock.url("api/facts"))
+            .await
+            .expect("Valid get");
+        let slow = tokio::spawn(reqwest::get(mock.url("slow")));
+        arrivals.recv().await.unwrap();
+
+        let shutdown = tokio::spawn(mock.shutdown());
+        release.add_permits(1);
+        let journal = shutdown.await.expect("Shutdown task");
+        let body: Value = slow
+            .await
+            .expect("Request task")
+            .expect("Valid get")
+            .json()
+            .await
+            .expect("Serde");
+        assert_eq!(body, json!("slow"));
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal[1].request.path, "/slow");
+
+        // The handle keeps the gateway alive, so the file comes from the shutdown
+        assert!(gateway.info().is_some());
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        assert_eq!(replays[0].when.path, "/api/facts");
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_and_records_websockets() {
+        let file_path = std::env::temp_dir().join("replay-mocker-shutdown-ws.json");
+        let file_path = file_path.to_str().unwrap();
+        let _ = remove_file(file_path);
+        let upstream = MockServer::new().with_mock(WebSocketMock::new(
+            "/chat",
+            vec![WsStep::expect("ping"), WsStep::send_after(0, "pong")],
+        ));
+        let mock = MockServer::new().with_mock(WebSocketGateway::new_replay(
+            "",
+            &format!("ws://{}", upstream.address),
+            file_path,
+        ));
+        let (mut socket, _) = connect_async(format!("ws://{}/chat", mock.address))
+            .await
+            .expect("Valid websocket");
+        socket
+            .send(Message::Text("ping".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text("pong".to_string())
+        );
+
+        // The client never leaves, shutting down closes the session instead of waiting on it
+        timeout(Duration::from_secs(5), mock.shutdown())
+            .await
+            .expect("Shutdown with an open websocket");
+        assert!(matches!(socket.next().await, Some(Ok(Message::Close(_)))));
+        let replays: Vec<Replay> =
+            serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap();
+        match &replays[0].then {
+            DynamicBody::WebSocket(steps) => {
+                assert_eq!(steps.len(), 2);
+                assert_eq!(steps[0], WsStep::expect("ping"));
+            }
+            other => panic!("Expected a websocket replay, got {:?}", other),
+        }
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
+
+    #[test]
+    fn blocking_server_test() {
+        let mock = MockServer::new_blocking().with_mock(ClosureMock::new(|_req| async {
+            Some(json!({"fact": "cats sleep a lot"}))
+        }));
+        let body: Value = reqwest::blocking::get(mock.url("facts"))
+            .expect("Valid get")
+            .json()
+            .expect("Serde");
+        assert_eq!(body, json!({"fact": "cats sleep a lot"}));
+
+        let journal = mock.shutdown_blocking();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].request.path, "/facts");
+    }
+
+    #[test]
+    fn blocking_server_watches_replays_test() {
+        let file_path = std::env::temp_dir().join("replay-mocker-watch-blocking.json");
+        let cassette = |then: Value| {
+            json!([{
+                "when": {"path": "/facts", "queries": null, "method": "Get", "body": null},
+                "then": {"Json": then}
+            }])
+            .to_string()
+        };
+        std::fs::write(&file_path, cassette(json!("v1"))).unwrap();
+        let mock = MockServer::new_blocking().with_mock(ReplayMock::watch_every(
+            file_path.to_str().unwrap(),
+            Duration::from_millis(20),
+        ));
+        let get = || -> Value {
+            reqwest::blocking::get(mock.url("facts"))
+                .expect("Valid get")
+                .json()
+                .expect("Serde")
+        };
+        assert_eq!(get(), json!("v1"));
+
+        std::fs::write(&file_path, cassette(json!("v2"))).unwrap();
+        let started = std::time::Instant::now();
+        while get() != json!("v2") {
+            assert!(started.elapsed() < Duration::from_secs(5), "No reload");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        mock.shutdown_blocking();
+        remove_file(file_path).expect("Remove the file for the testing");
+    }
 }
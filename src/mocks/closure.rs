@@ -20,7 +20,7 @@ impl<
         F: Fn(Request) -> T + Sync + Send,
     > ClosureMock<V, T, F>
 {
-    ///
+    /// Create a mock that runs the closure on every request
     pub fn new(closure: F) -> Box<ClosureMock<V, T, F>> {
         Box::new(Self { closure })
     }
@@ -21,7 +21,7 @@ impl<
         R: FnOnce(Request) -> T + Sync + Send,
     > FactoryClosure<V, T, F, R>
 {
-    ///
+    /// Create a mock that builds a new runner from the factory on every request
     pub fn new(closure: F) -> Box<FactoryClosure<V, T, F, R>> {
         Box::new(Self { closure })
     }
@@ -1,119 +1,526 @@
-use std::{fs::File, io::Write, sync::Mutex, time::Duration};
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use tracing::warn;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Value};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
 
-use crate::models::{DynamicBody, Method, Replay, Request};
+use crate::models::{
+    BodyStream, Chunk, DynamicBody, HttpVersion, Method, Recorder, Redaction, Replay, Request,
+    SseEvent, SseParser,
+};
 
 use super::RunMock;
 
+/// Responses bigger than this, or without a known length, are streamed through
+/// instead of being read into memory first, and are recorded as chunks with their timing.
+const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// Headers that only matter for one connection, so a proxy doesn't pass them on
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The headers worth passing on, without the hop by hop ones, and without the ones
+/// that are worked out again for the new message, like the length of a body we might re-encode
+pub(crate) fn forwarded_headers(
+    headers: &[(String, String)],
+    skip: &[&str],
+) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_lowercase();
+            !HOP_BY_HOP.contains(&name.as_str()) && !skip.contains(&name.as_str())
+        })
+        .cloned()
+        .collect()
+}
+
+/// The request as it is about to be sent upstream, for the hooks to change
+#[derive(Debug, Clone)]
+pub struct UpstreamRequest {
+    /// Where the request goes, like `https://example.com`, change it to send to another host
+    pub uri: String,
+    /// The path after the gateway path was taken off
+    pub path: String,
+    /// The raw query string, without the `?`
+    pub queries: Option<String>,
+    /// The method sent upstream
+    pub method: Method,
+    /// The headers sent upstream, the client's minus the hop by hop ones
+    pub headers: Vec<(String, String)>,
+    /// The body sent upstream
+    pub body: Option<DynamicBody>,
+}
+
+type RequestHook = Box<dyn Fn(&mut UpstreamRequest) + Send + Sync>;
+type ResponseHook = Box<dyn Fn(&mut DynamicBody) + Send + Sync>;
+
+/// Sets up a gateway and the http client it talks to the upstream with.
+/// The client is made once, so connections to the upstream are reused.
+pub struct GatewayBuilder {
+    path: String,
+    uri: String,
+    file: Option<String>,
+    client: Option<reqwest::Client>,
+    timeout: Duration,
+    root_certificates: Vec<reqwest::Certificate>,
+    proxy: Option<reqwest::Proxy>,
+    redirect: Option<reqwest::redirect::Policy>,
+    insecure: bool,
+    http2_prior_knowledge: bool,
+}
+impl GatewayBuilder {
+    /// Start a gateway that proxies the path to the uri
+    pub fn new(path: &str, uri: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            uri: uri.to_string(),
+            file: None,
+            client: None,
+            timeout: Duration::from_secs(60 * 5),
+            root_certificates: Vec::new(),
+            proxy: None,
+            redirect: None,
+            insecure: false,
+            http2_prior_knowledge: false,
+        }
+    }
+    /// Save the replays to the file when the gateway dies
+    pub fn replay_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+    /// Use this client as is, the other client options are ignored
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+    /// How long a call to the upstream can take, five minutes by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Trust this CA, in pem, for https upstreams. Panics on an invalid pem
+    pub fn root_certificate(mut self, pem: &str) -> Self {
+        self.root_certificates
+            .push(reqwest::Certificate::from_pem(pem.as_bytes()).expect("valid root certificate"));
+        self
+    }
+    /// Go through an http proxy to reach the upstream. Panics on an invalid proxy url
+    pub fn proxy(mut self, proxy_url: &str) -> Self {
+        self.proxy = Some(reqwest::Proxy::all(proxy_url).expect("valid proxy url"));
+        self
+    }
+    /// How redirects from the upstream are followed, up to 10 by default.
+    /// With `Policy::none()` the client gets the redirect itself.
+    pub fn redirect(mut self, policy: reqwest::redirect::Policy) -> Self {
+        self.redirect = Some(policy);
+        self
+    }
+    /// Accept any certificate from the upstream, for self signed upstreams
+    pub fn insecure(mut self) -> Self {
+        self.insecure = true;
+        self
+    }
+    /// Talk HTTP/2 to the upstream without negotiating it first, for h2c upstreams.
+    /// Https upstreams negotiate HTTP/2 on their own.
+    pub fn http2_prior_knowledge(mut self) -> Self {
+        self.http2_prior_knowledge = true;
+        self
+    }
+    /// Create the gateway, panics if the client can't be made
+    pub fn build(self) -> Box<Gateway> {
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let mut client = reqwest::Client::builder()
+                    .timeout(self.timeout)
+                    .danger_accept_invalid_certs(self.insecure);
+                for certificate in self.root_certificates {
+                    client = client.add_root_certificate(certificate);
+                }
+                if let Some(proxy) = self.proxy {
+                    client = client.proxy(proxy);
+                }
+                if let Some(policy) = self.redirect {
+                    client = client.redirect(policy);
+                }
+                if self.http2_prior_knowledge {
+                    client = client.http2_prior_knowledge();
+                }
+                client.build().expect("building the gateway client")
+            }
+        };
+        Box::new(Gateway {
+            path: self.path,
+            uri: self.uri,
+            client,
+            request_hooks: Vec::new(),
+            response_hooks: Vec::new(),
+            record_headers: true,
+            recorder: Arc::new(Recorder::new(self.file)),
+        })
+    }
+}
+
 /// Gateway is a proxy to another server. And when we get a response,
 /// We capture that in a value, so if we have a file name on deletion we create a replay
-/// for the replay mock
+/// for the replay mock. The upstream status is passed on and recorded too, so an upstream
+/// error or redirect is answered, not left to the next mock.
 pub struct Gateway {
     path: String,
     uri: String,
-    file: Option<String>,
-    replays: Mutex<Vec<Replay>>,
+    client: reqwest::Client,
+    request_hooks: Vec<RequestHook>,
+    response_hooks: Vec<ResponseHook>,
+    record_headers: bool,
+    recorder: Arc<Recorder>,
 }
 impl Gateway {
     /// Create a simple proxy server
     pub fn new(path: &str, uri: &str) -> Box<Self> {
-        Box::new(Self {
-            path: path.to_string(),
-            uri: uri.to_string(),
-            file: None,
-            replays: Default::default(),
-        })
+        GatewayBuilder::new(path, uri).build()
     }
     /// Create a proxy server that on death will create a replay file
     pub fn new_replay(path: &str, uri: &str, file: &str) -> Box<Self> {
-        Box::new(Self {
-            path: path.to_string(),
-            uri: uri.to_string(),
-            file: Some(file.to_string()),
-            replays: Default::default(),
+        GatewayBuilder::new(path, uri).replay_file(file).build()
+    }
+    /// Set up a proxy server with the client options, like timeouts and certificates
+    pub fn builder(path: &str, uri: &str) -> GatewayBuilder {
+        GatewayBuilder::new(path, uri)
+    }
+    /// Scrub secrets out of the replays before they are recorded
+    pub fn with_redaction(self: Box<Self>, redaction: Redaction) -> Box<Self> {
+        self.recorder.add_redaction(redaction);
+        self
+    }
+    /// Leave the request and response headers out of the replays, they are recorded by
+    /// default and the recorded response headers are sent again on replay.
+    pub fn without_recorded_headers(mut self: Box<Self>) -> Box<Self> {
+        self.record_headers = false;
+        self
+    }
+    /// Set a header on every request sent upstream, like an auth token the client doesn't have.
+    /// It replaces the header if the client sent one.
+    pub fn with_header(self: Box<Self>, name: &str, value: &str) -> Box<Self> {
+        let header = (name.to_string(), value.to_string());
+        self.with_request_hook(move |request| {
+            request
+                .headers
+                .retain(|(name, _)| !name.eq_ignore_ascii_case(&header.0));
+            request.headers.push(header.clone());
         })
     }
+    /// Change the request before it is sent upstream, to rewrite the path or host or
+    /// transform the body. The replay still records the request as it came in.
+    pub fn with_request_hook(
+        mut self: Box<Self>,
+        hook: impl Fn(&mut UpstreamRequest) + Send + Sync + 'static,
+    ) -> Box<Self> {
+        self.request_hooks.push(Box::new(hook));
+        self
+    }
+    /// Change the response body before it is returned and recorded, the upstream headers
+    /// are added after. Streamed responses come in as a stream, and are recorded as they
+    /// come from upstream.
+    pub fn with_response_hook(
+        mut self: Box<Self>,
+        hook: impl Fn(&mut DynamicBody) + Send + Sync + 'static,
+    ) -> Box<Self> {
+        self.response_hooks.push(Box::new(hook));
+        self
+    }
+}
+
+fn http_version(version: reqwest::Version) -> Option<HttpVersion> {
+    match version {
+        reqwest::Version::HTTP_10 => Some(HttpVersion::Http10),
+        reqwest::Version::HTTP_11 => Some(HttpVersion::Http11),
+        reqwest::Version::HTTP_2 => Some(HttpVersion::Http2),
+        reqwest::Version::HTTP_3 => Some(HttpVersion::Http3),
+        _ => None,
+    }
 }
 #[async_trait]
 impl RunMock for Gateway {
     async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
         let path = request.path.strip_prefix(&self.path)?;
-        println!("{:?}", request);
+        // We ask for the body as is, so what we record is readable
+        let request = &Request {
+            headers: forwarded_headers(
+                &request.headers,
+                &["host", "content-length", "accept-encoding"],
+            ),
+            ..request.clone()
+        };
 
+        let mut upstream = UpstreamRequest {
+            uri: self.uri.clone(),
+            path: path.to_string(),
+            queries: request.queries.clone(),
+            method: request.method.clone(),
+            headers: request.headers.clone(),
+            body: request.body.clone(),
+        };
+        for hook in self.request_hooks.iter() {
+            hook(&mut upstream);
+        }
         let uri = format!(
             "{}{}{}",
-            self.uri,
-            path,
-            request
+            upstream.uri,
+            upstream.path,
+            upstream
                 .queries
-                .clone()
+                .as_ref()
                 .map(|x| format!("?{}", x))
                 .unwrap_or_default()
         );
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60 * 5))
-            .build()
-            .ok()?;
-        let response = match request.method {
-            Method::Post => client.post(&uri),
-            Method::Put => client.put(&uri),
-            Method::Get => client.get(&uri),
-            Method::Delete => client.delete(&uri),
-            Method::Head => client.head(&uri),
-            Method::Patch => client.patch(&uri),
-            Method::Trace | Method::Connect | Method::Options | Method::Other => return None,
-        };
-        let response = match &request.body {
+        let method_string = upstream.method.as_method_string();
+        let method = reqwest::Method::from_bytes(method_string.as_bytes()).ok()?;
+        let response = self.client.request(method, &uri);
+        let response = upstream
+            .headers
+            .iter()
+            .fold(response, |response, (name, value)| {
+                response.header(name, value)
+            });
+        let response = match &upstream.body {
             &None => response,
             Some(DynamicBody::Text(body)) => response.body(body.clone()),
             Some(DynamicBody::Bytes(body)) => response.body(body.clone()),
-            Some(DynamicBody::Json(body)) => response
-                .header("content-type", "application/json")
-                .json(&body),
+            Some(DynamicBody::Json(body)) => response.json(&body),
+            Some(DynamicBody::Chunks(chunks)) => response.body(
+                chunks
+                    .iter()
+                    .flat_map(|chunk| chunk.data.iter().cloned())
+                    .collect::<Vec<_>>(),
+            ),
+            Some(DynamicBody::Events(events)) => response.body(
+                events
+                    .iter()
+                    .map(|event| event.to_wire())
+                    .collect::<String>(),
+            ),
+            Some(DynamicBody::File(file_path)) => {
+                let file = tokio::fs::File::open(file_path).await.ok()?;
+                response.body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+            }
+            Some(DynamicBody::Stream(body_stream)) => {
+                response.body(reqwest::Body::wrap_stream(body_stream.take()?))
+            }
+            Some(DynamicBody::WebSocket(_))
+            | Some(DynamicBody::WebSocketProxy(_))
+            | Some(DynamicBody::Grpc(_))
+            | Some(DynamicBody::GrpcFrames(_))
+            | Some(DynamicBody::WithHeaders { .. })
+            | Some(DynamicBody::WithStatus { .. }) => return None,
         };
 
-        let response = response.send().await.ok()?;
-        println!("response {:?}", response);
-        // And then, if the request gets a response...
-        if response.status() != 200 {
-            warn!("Error status: {}", response.status());
-            return None;
+        debug!(%uri, method = %method_string, "Forwarding upstream");
+        let started = Instant::now();
+        let response = match response.send().await {
+            Ok(response) => response,
+            Err(error) => {
+                warn!("Upstream {} failed: {}", uri, error);
+                return None;
+            }
+        };
+        tracing::Span::current().record("upstream_status", response.status().as_u16());
+        debug!(
+            %uri,
+            upstream_status = response.status().as_u16(),
+            upstream_ms = started.elapsed().as_millis() as u64,
+            "Upstream answered"
+        );
+        // Any status is passed on and recorded, like a redirect or a 204 to a preflight
+        let status = response.status().as_u16();
+        let version = http_version(response.version());
+        let recorded_request = Request {
+            headers: if self.record_headers {
+                request.headers.clone()
+            } else {
+                vec![]
+            },
+            ..request.clone()
+        };
+        let headers = forwarded_headers(
+            &response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect::<Vec<_>>(),
+            &["content-length"],
+        );
+        let is_event_stream = response
+            .headers()
+            .get("content-type")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.starts_with("text/event-stream"))
+            .unwrap_or(false);
+        let has_body = !matches!(status, 204 | 304);
+        let should_stream = has_body
+            && response
+                .content_length()
+                .is_none_or(|length| length > STREAM_THRESHOLD);
+        if is_event_stream || should_stream {
+            let stream = response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(io::Error::other));
+            let (recording, content_type) = if is_event_stream {
+                (
+                    Recording::Events(SseParser::default(), Vec::new()),
+                    Some("text/event-stream"),
+                )
+            } else {
+                (Recording::Chunks(Vec::new()), None)
+            };
+            let mut body_stream = BodyStream::new(TeeStream {
+                stream: Box::pin(stream),
+                request: Some(recorded_request),
+                version,
+                status,
+                headers: if self.record_headers {
+                    Some(headers.clone())
+                } else {
+                    None
+                },
+                recording,
+                last_recorded: Instant::now(),
+                recorder: self.recorder.clone(),
+            });
+            body_stream.content_type = content_type.map(String::from);
+            let mut response_body = DynamicBody::Stream(body_stream);
+            for hook in self.response_hooks.iter() {
+                hook(&mut response_body);
+            }
+            return Some(with_status(response_body, status).with_headers(headers));
         }
         let body_bytes = response.bytes().await.ok()?;
-        let response_body: DynamicBody = if let Some(json) =
-            serde_json::from_slice(&body_bytes).ok()
-        {
+        let mut response_body: DynamicBody = if let Ok(json) = serde_json::from_slice(&body_bytes) {
             DynamicBody::Json(json)
-        } else if let Some(body) = String::from_utf8(body_bytes.iter().cloned().collect()).ok() {
+        } else if let Ok(body) = String::from_utf8(body_bytes.iter().cloned().collect()) {
             DynamicBody::Text(body)
         } else {
             DynamicBody::Bytes(body_bytes.into_iter().collect())
         };
-        {
-            let mut replays = self.replays.lock().ok()?;
-            replays.push(Replay {
-                when: request.clone(),
-                then: response_body.clone(),
-            });
+        for hook in self.response_hooks.iter() {
+            hook(&mut response_body);
         }
+        let response_body = with_status(response_body, status);
+        self.recorder.record(Replay {
+            when: recorded_request,
+            then: if self.record_headers {
+                response_body.clone().with_headers(headers.clone())
+            } else {
+                response_body.clone()
+            },
+            version,
+        });
+
+        Some(response_body.with_headers(headers))
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "gateway": { "path": self.path, "uri": self.uri } })
+    }
 
-        Some(response_body)
+    fn flush(&self) {
+        self.recorder.flush();
     }
 }
 
-impl Drop for Gateway {
-    fn drop(&mut self) {
-        if let Some(file) = &self.file {
-            let replays = self.replays.lock().expect("getting replays");
-            if replays.is_empty() {
-                return;
+/// The body with the upstream status, a 200 is left as the plain body
+fn with_status(body: DynamicBody, status: u16) -> DynamicBody {
+    match status {
+        200 => body,
+        status => body.with_status(status),
+    }
+}
+
+/// What we are keeping of a stream while it passes through
+enum Recording {
+    Chunks(Vec<Chunk>),
+    Events(SseParser, Vec<SseEvent>),
+}
+
+/// Passes a stream from the upstream through, while keeping a copy of each chunk
+/// (or server sent event) and its timing. Once the stream is done we record it as a replay.
+struct TeeStream {
+    stream: Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>,
+    request: Option<Request>,
+    version: Option<HttpVersion>,
+    status: u16,
+    headers: Option<Vec<(String, String)>>,
+    recording: Recording,
+    last_recorded: Instant,
+    recorder: Arc<Recorder>,
+}
+
+impl Stream for TeeStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = self.stream.as_mut().poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(bytes))) => {
+                let now = Instant::now();
+                let delay_ms = now.duration_since(self.last_recorded).as_millis() as u64;
+                match &mut self.recording {
+                    Recording::Chunks(chunks) => {
+                        chunks.push(Chunk::new(delay_ms, bytes.to_vec()));
+                        self.last_recorded = now;
+                    }
+                    Recording::Events(parser, events) => {
+                        let parsed = parser.push(bytes);
+                        if !parsed.is_empty() {
+                            events.extend(parsed.into_iter().enumerate().map(|(index, event)| {
+                                event.with_delay(if index == 0 { delay_ms } else { 0 })
+                            }));
+                            self.last_recorded = now;
+                        }
+                    }
+                }
+            }
+            Poll::Ready(None) => {
+                if let Some(request) = self.request.take() {
+                    let then = match &mut self.recording {
+                        Recording::Chunks(chunks) => DynamicBody::Chunks(std::mem::take(chunks)),
+                        Recording::Events(_, events) => DynamicBody::Events(std::mem::take(events)),
+                    };
+                    let then = with_status(then, self.status);
+                    let then = match self.headers.take() {
+                        Some(headers) => then.with_headers(headers),
+                        None => then,
+                    };
+                    self.recorder.record(Replay {
+                        when: request,
+                        then,
+                        version: self.version,
+                    });
+                }
             }
-            let replays_bytes = serde_json::to_vec(&*replays).expect("Serializing the replays");
-            let mut file = File::create(file).expect("creating gateway file");
-            file.write_all(&replays_bytes)
-                .expect("writing gateway to file");
+            _ => (),
         }
+        polled
     }
 }
@@ -0,0 +1,92 @@
+use assert_json_diff::{CompareMode, Config};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::models::{DynamicBody, GraphQlRequest, Method, Request};
+
+use super::RunMock;
+
+struct Operation {
+    name: String,
+    variables: Option<Value>,
+    then: DynamicBody,
+}
+
+/// Serves GraphQL responses per operation, so we don't have to match on the
+/// whole body of every `POST /graphql`. The first match means the first reply.
+pub struct GraphQlMock {
+    path: String,
+    operations: Vec<Operation>,
+}
+impl GraphQlMock {
+    /// Create a mock for the GraphQL endpoint on the path, like `/graphql`
+    pub fn new(path: &str) -> Box<Self> {
+        Box::new(Self {
+            path: path.to_string(),
+            operations: vec![],
+        })
+    }
+    /// Respond to any request for the operation
+    pub fn with_operation(
+        mut self: Box<Self>,
+        name: &str,
+        then: impl Into<DynamicBody>,
+    ) -> Box<Self> {
+        self.operations.push(Operation {
+            name: name.to_string(),
+            variables: None,
+            then: then.into(),
+        });
+        self
+    }
+    /// Respond to requests for the operation, when the variables include these
+    pub fn with_operation_variables(
+        mut self: Box<Self>,
+        name: &str,
+        variables: Value,
+        then: impl Into<DynamicBody>,
+    ) -> Box<Self> {
+        self.operations.push(Operation {
+            name: name.to_string(),
+            variables: Some(variables),
+            then: then.into(),
+        });
+        self
+    }
+}
+#[async_trait]
+impl RunMock for GraphQlMock {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        if request.method != Method::Post || request.path != self.path {
+            return None;
+        }
+        let graphql = match &request.body {
+            Some(DynamicBody::Json(body)) => GraphQlRequest::from_json_lenient(body)?,
+            _ => return None,
+        };
+        let operation_name = graphql.operation_name.as_deref()?;
+        self.operations
+            .iter()
+            .find(|operation| {
+                operation.name == operation_name
+                    && operation.variables.as_ref().is_none_or(|variables| {
+                        assert_json_diff::assert_json_matches_no_panic(
+                            &graphql.variables,
+                            variables,
+                            Config::new(CompareMode::Inclusive),
+                        )
+                        .is_ok()
+                    })
+            })
+            .map(|operation| operation.then.clone())
+    }
+
+    fn describe(&self) -> Value {
+        let operations = self
+            .operations
+            .iter()
+            .map(|operation| operation.name.as_str())
+            .collect::<Vec<_>>();
+        json!({ "graphql": { "path": self.path, "operations": operations } })
+    }
+}
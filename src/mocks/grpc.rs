@@ -0,0 +1,459 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tracing::warn;
+use warp::hyper::{self, body::HttpBody, client::HttpConnector, HeaderMap};
+
+use crate::{
+    header_pairs,
+    models::{
+        decode_grpc_frames, BodyStream, DynamicBody, GrpcFrames, GrpcResponse, GrpcStatus,
+        HttpVersion, Method, Recorder, Redaction, Replay, ReplayIndex, Request,
+    },
+};
+
+use super::{describe_replays, gateway::forwarded_headers, RunMock};
+
+/// The protobuf descriptors of the services we mock, loaded from a
+/// `FileDescriptorSet` like the one `protoc --descriptor_set_out` writes.
+#[derive(Debug, Clone)]
+pub struct GrpcDescriptors {
+    pool: DescriptorPool,
+}
+impl GrpcDescriptors {
+    /// Load the descriptors from an encoded `FileDescriptorSet`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            pool: DescriptorPool::decode(bytes).expect("parse descriptors"),
+        }
+    }
+    /// Load the descriptors from a `FileDescriptorSet` file
+    pub fn from_file(path: &str) -> Self {
+        let mut bytes = vec![];
+        File::open(path)
+            .expect("descriptors from file")
+            .read_to_end(&mut bytes)
+            .expect("read descriptors");
+        Self::from_bytes(&bytes)
+    }
+
+    /// Find the method for a gRPC path, like `/package.Service/Method`
+    fn method(&self, path: &str) -> Option<MethodDescriptor> {
+        let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+        self.pool
+            .get_service_by_name(service)?
+            .methods()
+            .find(|x| x.name() == method)
+    }
+
+    /// Decode a gRPC request into the json form that replays match on.
+    /// Client streaming calls have all the messages in an array.
+    fn decode_request(&self, request: &Request) -> Option<(MethodDescriptor, Request)> {
+        let method = self.method(&request.path)?;
+        let body = match &request.body {
+            Some(DynamicBody::Bytes(bytes)) => bytes.clone(),
+            Some(DynamicBody::Text(text)) => text.clone().into_bytes(),
+            None => vec![],
+            Some(_) => return None,
+        };
+        let decoded = decoded_request(&method, request, &body)?;
+        Some((method, decoded))
+    }
+}
+
+/// The request with its body as the json that replays match on.
+/// Client streaming calls have all the messages in an array.
+fn decoded_request(method: &MethodDescriptor, request: &Request, body: &[u8]) -> Option<Request> {
+    let messages = decode_grpc_frames(body)?
+        .iter()
+        .map(|message| decode_message(method.input(), message))
+        .collect::<Option<Vec<_>>>()?;
+    let body = if method.is_client_streaming() {
+        Value::Array(messages)
+    } else {
+        messages
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| Value::Object(Default::default()))
+    };
+    Some(Request {
+        body: Some(DynamicBody::Json(body)),
+        ..request.clone()
+    })
+}
+
+fn decode_message(descriptor: MessageDescriptor, bytes: &[u8]) -> Option<Value> {
+    let message = DynamicMessage::decode(descriptor, bytes).ok()?;
+    serde_json::to_value(&message).ok()
+}
+
+fn encode_message(descriptor: MessageDescriptor, value: &Value) -> Option<Vec<u8>> {
+    let message = DynamicMessage::deserialize(descriptor, value).ok()?;
+    Some(message.encode_to_vec())
+}
+
+fn encode_response(method: &MethodDescriptor, response: &GrpcResponse) -> GrpcFrames {
+    let messages = response
+        .messages
+        .iter()
+        .map(|message| encode_message(method.output(), message))
+        .collect::<Option<Vec<_>>>();
+    match messages {
+        Some(messages) => GrpcFrames {
+            messages,
+            status: response.status.clone(),
+        },
+        None => {
+            warn!(
+                "Can't encode the response of {} as {}",
+                method.full_name(),
+                method.output().full_name()
+            );
+            GrpcFrames {
+                messages: vec![],
+                status: GrpcStatus::error(13, "response does not match the descriptor"),
+            }
+        }
+    }
+}
+
+/// We want to replay gRPC calls, matching on the decoded request messages
+/// instead of the raw bytes. The first match means the first reply.
+pub struct GrpcMock {
+    path: String,
+    descriptors: GrpcDescriptors,
+    replays: ReplayIndex,
+}
+impl GrpcMock {
+    /// Creating a gRPC mock with a known set of replays, the `when` bodies are
+    /// the json form of the request message
+    pub fn new(descriptors: GrpcDescriptors, replays: Vec<Replay>) -> Box<Self> {
+        Box::new(Self {
+            path: String::new(),
+            descriptors,
+            replays: ReplayIndex::new(replays),
+        })
+    }
+    /// Answer the calls under a path, like `/grpc`, the way a `GrpcGateway` on that path
+    /// records them. The replays have the path after it, like `/package.Service/Method`.
+    pub fn with_path(mut self: Box<Self>, path: &str) -> Box<Self> {
+        self.path = path.to_string();
+        self
+    }
+    /// Creating a gRPC mock with a known set of replays as a json file
+    pub fn from_file(descriptors: GrpcDescriptors, path: &str) -> Box<Self> {
+        let file = File::open(path).expect("replay from file");
+        let replays = serde_json::from_reader(&file).expect("parse replay");
+        Self::new(descriptors, replays)
+    }
+}
+#[async_trait]
+impl RunMock for GrpcMock {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        let path = request.path.strip_prefix(&self.path)?;
+        let request = &Request {
+            path: path.to_string(),
+            ..request.clone()
+        };
+        let (method, decoded) = self.descriptors.decode_request(request)?;
+        let replay = self.replays.find(&decoded)?;
+        match &replay.then {
+            DynamicBody::Grpc(response) => {
+                Some(DynamicBody::GrpcFrames(encode_response(&method, response)))
+            }
+            then => Some(then.clone()),
+        }
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "grpc": describe_replays(self.replays.replays()) })
+    }
+}
+
+/// GrpcGateway is a gRPC proxy to another server. Messages are passed on both ways as
+/// they come, so streaming calls stay live, and the caller's metadata goes upstream.
+/// Each call is captured with the messages decoded to json once it ends, so if we have
+/// a file name on deletion we create a replay for the gRPC mock.
+/// The replays have the path after the gateway path, the gRPC method.
+/// Compressed calls are proxied but not recorded, the messages can't be decoded.
+pub struct GrpcGateway {
+    path: String,
+    uri: String,
+    descriptors: GrpcDescriptors,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    recorder: Arc<Recorder>,
+}
+impl GrpcGateway {
+    /// Create a simple gRPC proxy, the uri is like `http://localhost:50051`
+    pub fn new(path: &str, uri: &str, descriptors: GrpcDescriptors) -> Box<Self> {
+        Self::create(path, uri, descriptors, None)
+    }
+    /// Create a gRPC proxy that on death will create a replay file
+    pub fn new_replay(
+        path: &str,
+        uri: &str,
+        descriptors: GrpcDescriptors,
+        file: &str,
+    ) -> Box<Self> {
+        Self::create(path, uri, descriptors, Some(file.to_string()))
+    }
+    fn create(
+        path: &str,
+        uri: &str,
+        descriptors: GrpcDescriptors,
+        file: Option<String>,
+    ) -> Box<Self> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http2()
+            .build();
+        Box::new(Self {
+            path: path.to_string(),
+            uri: uri.to_string(),
+            descriptors,
+            client: hyper::Client::builder().http2_only(true).build(connector),
+            recorder: Arc::new(Recorder::new(file)),
+        })
+    }
+    /// Scrub secrets out of the replays before they are recorded
+    pub fn with_redaction(self: Box<Self>, redaction: Redaction) -> Box<Self> {
+        self.recorder.add_redaction(redaction);
+        self
+    }
+}
+
+fn grpc_status(metadata: &HeaderMap) -> Option<GrpcStatus> {
+    let code = metadata.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+    let message = metadata
+        .get("grpc-message")
+        .and_then(|x| x.to_str().ok())
+        .map(String::from);
+    let trailers = metadata
+        .iter()
+        .filter(|(key, _)| !key.as_str().starts_with("grpc-") && !key.as_str().ends_with("-bin"))
+        .filter(|(key, _)| key.as_str() != "content-type")
+        .filter_map(|(key, value)| Some((key.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    Some(GrpcStatus {
+        code,
+        message,
+        trailers,
+    })
+}
+
+/// What a gRPC gateway needs to record a call once it ends
+struct GrpcRecording {
+    method: MethodDescriptor,
+    request: Request,
+    sent: Arc<Mutex<Vec<u8>>>,
+    recorder: Arc<Recorder>,
+}
+
+impl GrpcRecording {
+    fn record(self, received: &[u8], status: GrpcStatus) {
+        let sent = self.sent.lock().unwrap();
+        let when = decoded_request(&self.method, &self.request, &sent);
+        let messages = decode_grpc_frames(received).and_then(|messages| {
+            messages
+                .iter()
+                .map(|message| decode_message(self.method.output(), message))
+                .collect::<Option<Vec<_>>>()
+        });
+        match (when, messages) {
+            (Some(when), Some(messages)) => self.recorder.record(Replay {
+                when,
+                then: DynamicBody::Grpc(GrpcResponse { messages, status }),
+                version: Some(HttpVersion::Http2),
+            }),
+            _ => warn!(
+                "Can't decode the gRPC call to {}, it isn't recorded",
+                self.method.full_name()
+            ),
+        }
+    }
+}
+
+/// A gRPC response on its way through the gateway
+struct GrpcProxied {
+    body: hyper::Body,
+    headers: HeaderMap,
+    received: Vec<u8>,
+    trailers: oneshot::Sender<HeaderMap>,
+    recording: Option<GrpcRecording>,
+}
+
+impl GrpcProxied {
+    /// Pass the trailers on, and record the call now that it is done
+    async fn finish(mut self) {
+        let trailers = match self.body.trailers().await {
+            Ok(Some(trailers)) => trailers,
+            // A trailers only response has the status in the headers
+            _ => self
+                .headers
+                .iter()
+                .filter(|(key, _)| key.as_str() == "grpc-status" || key.as_str() == "grpc-message")
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        };
+        let status =
+            grpc_status(&trailers).unwrap_or_else(|| GrpcStatus::error(13, "missing grpc-status"));
+        if let Some(recording) = self.recording.take() {
+            recording.record(&self.received, status);
+        }
+        let _ = self.trailers.send(trailers);
+    }
+}
+
+fn is_compressed(encoding: Option<&str>) -> bool {
+    encoding.is_some_and(|x| x != "identity")
+}
+
+#[async_trait]
+impl RunMock for GrpcGateway {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        if request.method != Method::Post {
+            return None;
+        }
+        let path = request.path.strip_prefix(&self.path)?;
+        let method = self.descriptors.method(path)?;
+        let compressed = is_compressed(
+            request
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("grpc-encoding"))
+                .map(|(_, value)| value.as_str()),
+        );
+        if compressed {
+            warn!(
+                "The gRPC call to {} is compressed, it isn't recorded",
+                request.path
+            );
+        }
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let body = match &request.body {
+            Some(DynamicBody::Stream(body_stream)) => {
+                let sent = sent.clone();
+                let stream = body_stream.take()?.inspect(move |chunk| {
+                    if let Ok(chunk) = chunk {
+                        sent.lock().unwrap().extend_from_slice(chunk);
+                    }
+                });
+                hyper::Body::wrap_stream(stream)
+            }
+            Some(DynamicBody::Bytes(bytes)) => {
+                sent.lock().unwrap().extend_from_slice(bytes);
+                hyper::Body::from(bytes.clone())
+            }
+            None => hyper::Body::empty(),
+            Some(_) => return None,
+        };
+        let metadata = forwarded_headers(
+            &request.headers,
+            &[
+                "host",
+                "content-length",
+                "content-type",
+                "grpc-accept-encoding",
+            ],
+        );
+        // We ask for the messages as is, so what we record can be decoded
+        let upstream = metadata.iter().fold(
+            hyper::Request::post(format!("{}{}", self.uri, path)),
+            |upstream, (name, value)| upstream.header(name, value),
+        );
+        let upstream = upstream
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("grpc-accept-encoding", "identity")
+            .body(body)
+            .ok()?;
+        let response = match self.client.request(upstream).await {
+            Ok(response) => response,
+            Err(error) => {
+                warn!("gRPC call to {} failed: {}", self.uri, error);
+                return None;
+            }
+        };
+        let (parts, body) = response.into_parts();
+        let response_compressed = is_compressed(
+            parts
+                .headers
+                .get("grpc-encoding")
+                .and_then(|x| x.to_str().ok()),
+        );
+        if response_compressed {
+            warn!(
+                "The gRPC response from {} is compressed, it isn't recorded",
+                self.uri
+            );
+        }
+        let response_metadata = forwarded_headers(
+            &header_pairs(&parts.headers),
+            &[
+                "content-type",
+                "content-length",
+                "grpc-status",
+                "grpc-message",
+            ],
+        );
+        let (trailers, trailers_receiver) = oneshot::channel();
+        let proxied = GrpcProxied {
+            body,
+            headers: parts.headers,
+            received: vec![],
+            trailers,
+            recording: (!compressed && !response_compressed).then(|| GrpcRecording {
+                method,
+                request: Request {
+                    path: path.to_string(),
+                    headers: vec![],
+                    ..request.clone()
+                },
+                sent,
+                recorder: self.recorder.clone(),
+            }),
+        };
+        let stream = stream::unfold(Some(proxied), |proxied| async move {
+            let mut proxied = proxied?;
+            match proxied.body.data().await {
+                Some(Ok(chunk)) => {
+                    proxied.received.extend_from_slice(&chunk);
+                    Some((Ok(chunk), Some(proxied)))
+                }
+                Some(Err(error)) => Some((Err(io::Error::other(error)), None)),
+                None => {
+                    proxied.finish().await;
+                    None
+                }
+            }
+        });
+        let body_stream = BodyStream::new(stream)
+            .with_content_type("application/grpc")
+            .with_trailers(trailers_receiver);
+        Some(DynamicBody::Stream(body_stream).with_headers(response_metadata))
+    }
+
+    fn takes_streamed_body(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "grpc_gateway": { "path": self.path, "uri": self.uri } })
+    }
+
+    fn flush(&self) {
+        self.recorder.flush();
+    }
+}
@@ -1,19 +1,66 @@
 use async_trait::async_trait;
+use serde_json::{json, Value};
 
-use crate::models::{DynamicBody, Request};
+use crate::models::{DynamicBody, Replay, Request};
 mod closure;
 mod factory_closure;
 mod gateway;
+mod graphql;
+mod grpc;
 mod replay;
+mod sse;
+mod websocket;
 
 pub use closure::*;
 pub use factory_closure::*;
 pub use gateway::*;
+pub use graphql::*;
+pub use grpc::*;
 pub use replay::*;
+pub use sse::*;
+pub use websocket::*;
+
+/// How many routes a mock with replays lists when it is described
+const DESCRIBED_ROUTES: usize = 5;
+
+/// How many replays there are and the first routes, the replays can be too big to list
+fn describe_replays(replays: &[Replay]) -> Value {
+    let routes = replays
+        .iter()
+        .take(DESCRIBED_ROUTES)
+        .map(|replay| {
+            format!(
+                "{} {}",
+                replay.when.method.as_method_string(),
+                replay.when.path
+            )
+        })
+        .collect::<Vec<_>>();
+    json!({ "count": replays.len(), "routes": routes })
+}
+
 #[async_trait]
 /// Want to test a route to see if this mock works, hence the option.
 /// When there is a value it expects that we are using this mock and stops here.
 pub trait RunMock {
-    ///
+    /// Run the mock against the request, `None` means this mock does not handle it
     async fn run_mock(&self, request: &Request) -> Option<DynamicBody>;
+
+    /// What this mock does, as json, for listing the mocks of a running server
+    fn describe(&self) -> Value {
+        Value::Null
+    }
+
+    /// Write out what the mock recorded so far, the server calls it when shutting down
+    fn flush(&self) {}
+
+    /// Start the background work of the mock, like watching files, on the runtime of the
+    /// server it was added to. Called when the mock is added.
+    fn start(&self, _runtime: &tokio::runtime::Handle) {}
+
+    /// If the mock can take a gRPC request body as it streams in. Otherwise the body
+    /// is read whole before the mock runs.
+    fn takes_streamed_body(&self) -> bool {
+        false
+    }
 }
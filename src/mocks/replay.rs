@@ -1,20 +1,48 @@
-use std::fs::File;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
 
 use async_trait::async_trait;
+use globset::{Glob, GlobMatcher};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
 
-use crate::models::{DynamicBody, Replay, Request};
+use crate::models::{cassette_files, Cassettes, DynamicBody, Replay, ReplayIndex, Request};
 
-use super::RunMock;
+use super::{describe_replays, RunMock};
+
+/// How often a watched replay file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
 /// We want to be able to replay from a set of replay sets, and the
-/// first match means the first reply.
+/// first match means the first reply. The replays are indexed by method and path,
+/// so big cassettes stay quick.
 pub struct ReplayMock {
-    replays: Vec<Replay>,
+    replays: Arc<RwLock<ReplayIndex>>,
+    speed: f64,
+    /// What to watch, until the watcher is started
+    watch: Mutex<Option<Watch>>,
 }
 impl ReplayMock {
     /// Creating  a replay mock with a known set of replays
     pub fn new(replays: Vec<Replay>) -> Box<Self> {
-        Box::new(Self { replays })
+        Box::new(Self {
+            replays: Arc::new(RwLock::new(ReplayIndex::new(replays))),
+            speed: 1.0,
+            watch: Mutex::new(None),
+        })
+    }
+    /// Play timed bodies (chunks and events) at a different speed,
+    /// a speed of 2.0 plays twice as fast and 0.0 skips the delays
+    pub fn with_speed(mut self: Box<Self>, speed: f64) -> Box<Self> {
+        self.speed = speed;
+        self
     }
     /// Creating  a replay mock with a known set of replays as a json file
     pub fn from_file(path: &str) -> Box<Self> {
@@ -22,15 +50,173 @@ impl ReplayMock {
         let replays = serde_json::from_reader(&file).expect("parse replay");
         Self::new(replays)
     }
+    /// Creating  a replay mock from the json of a cassette, like one embedded with `include_str!`
+    pub fn from_json(json: &str) -> Box<Self> {
+        Self::new(serde_json::from_str(json).expect("parse replay"))
+    }
+    /// Creating a replay mock from the cassettes in a directory tree matching the glob,
+    /// like `**/*.json`. Duplicate `when` clauses are logged, the first cassette wins.
+    pub fn from_dir(dir: &str, glob: &str) -> Box<Self> {
+        Self::from_cassettes(Cassettes::new().with_dir(dir, glob))
+    }
+    /// Creating a replay mock from merged cassettes, logging the duplicate `when` clauses
+    pub fn from_cassettes(cassettes: Cassettes) -> Box<Self> {
+        Self::new(checked_replays(cassettes))
+    }
+    /// Creating a replay mock from a json file, or a directory tree of json files loaded
+    /// like `from_dir` with `**/*.json`, that is reloaded when it changes.
+    /// If the new replays don't parse we keep the old ones.
+    /// The watching runs on the server the mock is added to, so it works with `new_blocking` too.
+    pub fn watch(path: &str) -> Box<Self> {
+        Self::watch_every(path, WATCH_INTERVAL)
+    }
+    /// Like `watch`, checking for changes at the interval
+    pub fn watch_every(path: &str, interval: Duration) -> Box<Self> {
+        let path = PathBuf::from(path);
+        // Taken before loading, so an edit made while loading is picked up
+        let loaded = fingerprint(&path);
+        let replays = load_replays(&path).expect("replay from watched path");
+        let mock = Self::new(replays);
+        *mock.watch.lock().unwrap() = Some(Watch {
+            path,
+            interval,
+            loaded,
+        });
+        mock
+    }
+}
+
+/// The replays of the cassettes, logging the duplicate `when` clauses
+fn checked_replays(cassettes: Cassettes) -> Vec<Replay> {
+    for duplicate in cassettes.duplicates() {
+        if duplicate.conflicting {
+            warn!(
+                "Conflicting replays for {:?} {}, using the one from {}",
+                duplicate.when.method, duplicate.when.path, duplicate.sources[0]
+            );
+        } else {
+            info!(
+                "Duplicate replays for {:?} {} in {:?}",
+                duplicate.when.method, duplicate.when.path, duplicate.sources
+            );
+        }
+    }
+    cassettes.replays()
+}
+
+/// The json cassettes a watched path is loaded from
+fn watched_glob() -> GlobMatcher {
+    Glob::new("**/*.json")
+        .expect("valid glob")
+        .compile_matcher()
+}
+
+/// The replays of a watched file or directory, loaded the same way as `from_dir`
+fn load_replays(path: &Path) -> io::Result<Vec<Replay>> {
+    Ok(checked_replays(
+        Cassettes::new().try_with_path(path, &watched_glob())?,
+    ))
+}
+
+/// The files with their mtime and a hash of their contents
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// A watched path, and how often it is checked, waiting for the mock to be started
+struct Watch {
+    path: PathBuf,
+    interval: Duration,
+    /// The files as they were when the replays were loaded
+    loaded: Fingerprint,
 }
+
+/// Something that changes when the files are edited, added or removed. The contents are
+/// hashed, on filesystems with a coarse mtime an edit that keeps the length looks the same.
+fn fingerprint(path: &Path) -> Fingerprint {
+    cassette_files(path, &watched_glob())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| {
+            let modified = fs::metadata(&file).and_then(|x| x.modified()).ok();
+            let mut hasher = DefaultHasher::new();
+            fs::read(&file).unwrap_or_default().hash(&mut hasher);
+            (file, modified, hasher.finish())
+        })
+        .collect()
+}
+
+/// Do the file work for the path on the blocking threads, None if the runtime is going away
+async fn off_runtime<T: Send + 'static>(path: &Path, work: fn(&Path) -> T) -> Option<T> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || work(&path)).await.ok()
+}
+
+/// Reload the replays when the files change, until the mock is gone
+async fn watch_replays(watch: Watch, replays: Weak<RwLock<ReplayIndex>>) {
+    let Watch {
+        path,
+        interval,
+        loaded: mut last,
+    } = watch;
+    loop {
+        tokio::time::sleep(interval).await;
+        if replays.strong_count() == 0 {
+            return;
+        }
+        let current = match off_runtime(&path, fingerprint).await {
+            Some(current) => current,
+            None => return,
+        };
+        if current == last {
+            continue;
+        }
+        last = current;
+        let reloaded = match off_runtime(&path, load_replays).await {
+            Some(reloaded) => reloaded,
+            None => return,
+        };
+        let replays = match replays.upgrade() {
+            Some(replays) => replays,
+            None => return,
+        };
+        match reloaded {
+            Ok(reloaded) => {
+                info!("Reloaded {} replays from {:?}", reloaded.len(), path);
+                *replays.write().unwrap() = ReplayIndex::new(reloaded);
+            }
+            Err(error) => warn!(
+                "Keeping the old replays, can't reload {:?}: {}",
+                path, error
+            ),
+        }
+    }
+}
+
 #[async_trait]
 impl RunMock for ReplayMock {
     async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
-        for replay in self.replays.iter() {
-            if replay.matches_request(request) {
-                return Some(replay.then.clone());
+        let replays = self.replays.read().unwrap();
+        let replay = match replays.find(request) {
+            Some(replay) => replay,
+            None if replays.routes_to(request) => {
+                debug!("No replay for the method and path matched the query and body");
+                return None;
+            }
+            None => {
+                debug!("No replay for the method and path");
+                return None;
             }
+        };
+        Some(replay.then.clone().with_speed(self.speed))
+    }
+
+    fn start(&self, runtime: &tokio::runtime::Handle) {
+        if let Some(watch) = self.watch.lock().unwrap().take() {
+            runtime.spawn(watch_replays(watch, Arc::downgrade(&self.replays)));
         }
-        None
+    }
+
+    fn describe(&self) -> Value {
+        let replays = self.replays.read().unwrap();
+        json!({ "replay": describe_replays(replays.replays()) })
     }
 }
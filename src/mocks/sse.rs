@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::models::{DynamicBody, Method, Request, SseEvent};
+
+use super::RunMock;
+
+/// Serves a server sent event stream on a path, from a scripted list of events.
+/// Each event waits for its delay before being sent.
+pub struct SseMock {
+    path: String,
+    events: Vec<SseEvent>,
+}
+impl SseMock {
+    /// Create a mock that streams the events on a `GET` to the path
+    pub fn new(path: &str, events: Vec<SseEvent>) -> Box<Self> {
+        Box::new(Self {
+            path: path.to_string(),
+            events,
+        })
+    }
+}
+#[async_trait]
+impl RunMock for SseMock {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        if request.method != Method::Get || request.path != self.path {
+            return None;
+        }
+        Some(DynamicBody::Events(self.events.clone()))
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "sse": { "path": self.path, "events": self.events.len() } })
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde_json::{json, Value};
+use tokio::time::sleep;
+use tokio_tungstenite::{connect_async, tungstenite};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+use warp::ws::{Message, WebSocket};
+
+use crate::models::{
+    DynamicBody, Recorder, Redaction, Replay, Request, WsMessage, WsProxy, WsStep,
+};
+
+use super::RunMock;
+
+/// Serves a scripted conversation to websocket upgrades on a path, plain requests pass it by.
+pub struct WebSocketMock {
+    path: String,
+    steps: Vec<WsStep>,
+}
+impl WebSocketMock {
+    /// Create a mock that runs the script for websockets opened on the path
+    pub fn new(path: &str, steps: Vec<WsStep>) -> Box<Self> {
+        Box::new(Self {
+            path: path.to_string(),
+            steps,
+        })
+    }
+}
+#[async_trait]
+impl RunMock for WebSocketMock {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        if request.path != self.path || !request.is_websocket_upgrade() {
+            return None;
+        }
+        Some(DynamicBody::WebSocket(self.steps.clone()))
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "websocket": { "path": self.path, "steps": self.steps.len() } })
+    }
+}
+
+/// WebSocketGateway is a proxy for websockets to another server. Each session is
+/// captured as a scripted conversation, so if we have a file name on deletion we create
+/// a replay for the replay mock. Only websocket upgrades are proxied.
+pub struct WebSocketGateway {
+    path: String,
+    uri: String,
+    recorder: Arc<Recorder>,
+}
+impl WebSocketGateway {
+    /// Create a simple websocket proxy, the uri is like `ws://localhost:8080`
+    pub fn new(path: &str, uri: &str) -> Box<Self> {
+        Box::new(Self {
+            path: path.to_string(),
+            uri: uri.to_string(),
+            recorder: Default::default(),
+        })
+    }
+    /// Create a websocket proxy that on death will create a replay file
+    pub fn new_replay(path: &str, uri: &str, file: &str) -> Box<Self> {
+        Box::new(Self {
+            path: path.to_string(),
+            uri: uri.to_string(),
+            recorder: Arc::new(Recorder::new(Some(file.to_string()))),
+        })
+    }
+    /// Scrub secrets out of the replays before they are recorded
+    pub fn with_redaction(self: Box<Self>, redaction: Redaction) -> Box<Self> {
+        self.recorder.add_redaction(redaction);
+        self
+    }
+}
+#[async_trait]
+impl RunMock for WebSocketGateway {
+    async fn run_mock(&self, request: &Request) -> Option<DynamicBody> {
+        if !request.is_websocket_upgrade() {
+            return None;
+        }
+        let path = request.path.strip_prefix(&self.path)?;
+        let upstream = format!(
+            "{}{}{}",
+            self.uri,
+            path,
+            request
+                .queries
+                .clone()
+                .map(|x| format!("?{}", x))
+                .unwrap_or_default()
+        );
+        Some(DynamicBody::WebSocketProxy(Box::new(WsProxy {
+            upstream,
+            request: Request {
+                headers: vec![],
+                ..request.clone()
+            },
+            recorder: self.recorder.clone(),
+        })))
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "websocket_gateway": { "path": self.path, "uri": self.uri } })
+    }
+
+    fn flush(&self) {
+        self.recorder.flush();
+    }
+}
+
+fn from_warp(message: &Message) -> Option<WsMessage> {
+    if message.is_text() {
+        Some(WsMessage::Text(message.to_str().ok()?.to_string()))
+    } else if message.is_binary() {
+        Some(WsMessage::Binary(message.as_bytes().to_vec()))
+    } else {
+        None
+    }
+}
+
+fn to_warp(message: WsMessage) -> Message {
+    match message {
+        WsMessage::Text(text) => Message::text(text),
+        WsMessage::Binary(bytes) => Message::binary(bytes),
+        WsMessage::Json(value) => Message::text(value.to_string()),
+    }
+}
+
+/// Run a scripted conversation on an upgraded websocket, until the server is closing
+pub(crate) async fn run_ws_script(
+    socket: WebSocket,
+    steps: Vec<WsStep>,
+    closing: CancellationToken,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let closed = tokio::select! {
+        _ = play_ws_script(&mut sender, &mut receiver, steps) => false,
+        _ = closing.cancelled() => true,
+    };
+    if closed {
+        let _ = sender.send(Message::close()).await;
+    }
+}
+
+async fn play_ws_script(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    steps: Vec<WsStep>,
+) {
+    for step in steps {
+        match step {
+            WsStep::Expect(expected) => loop {
+                let received = match receiver.next().await {
+                    Some(Ok(received)) if !received.is_close() => received,
+                    _ => return,
+                };
+                let received = match from_warp(&received) {
+                    Some(received) => received,
+                    None => continue,
+                };
+                if expected.matches(&received) {
+                    break;
+                }
+                warn!(
+                    "Websocket expected {:?} but received {:?}",
+                    expected, received
+                );
+                let _ = sender
+                    .send(Message::close_with(1008u16, "unexpected message"))
+                    .await;
+                return;
+            },
+            WsStep::Send { delay_ms, message } => {
+                sleep(Duration::from_millis(delay_ms)).await;
+                if sender.send(to_warp(message)).await.is_err() {
+                    return;
+                }
+            }
+            WsStep::Close => {
+                let _ = sender.send(Message::close()).await;
+                return;
+            }
+        }
+    }
+    // The script is done, keep the connection open until the client leaves
+    while let Some(Ok(message)) = receiver.next().await {
+        if message.is_close() {
+            break;
+        }
+    }
+}
+
+/// Proxy an upgraded websocket to the upstream, recording the conversation
+/// until one side leaves or the server is closing
+pub(crate) async fn run_ws_proxy(socket: WebSocket, proxy: WsProxy, closing: CancellationToken) {
+    let upstream = match connect_async(proxy.upstream.as_str()).await {
+        Ok((upstream, _)) => upstream,
+        Err(error) => {
+            warn!("Can't connect websocket to {}: {}", proxy.upstream, error);
+            return;
+        }
+    };
+    let (mut upstream_sender, mut upstream_receiver) = upstream.split();
+    let (mut sender, mut receiver) = socket.split();
+    let mut steps = vec![];
+    let mut last_step = Instant::now();
+    loop {
+        tokio::select! {
+            _ = closing.cancelled() => {
+                let _ = upstream_sender.send(tungstenite::Message::Close(None)).await;
+                let _ = sender.send(Message::close()).await;
+                break;
+            }
+            message = receiver.next() => {
+                let message = match message {
+                    Some(Ok(message)) if !message.is_close() => message,
+                    _ => {
+                        let _ = upstream_sender.send(tungstenite::Message::Close(None)).await;
+                        break;
+                    }
+                };
+                let message = match from_warp(&message) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                last_step = Instant::now();
+                steps.push(WsStep::Expect(message.clone()));
+                let forwarded = match message {
+                    WsMessage::Binary(bytes) => tungstenite::Message::Binary(bytes),
+                    WsMessage::Text(text) => tungstenite::Message::Text(text),
+                    WsMessage::Json(value) => tungstenite::Message::Text(value.to_string()),
+                };
+                if upstream_sender.send(forwarded).await.is_err() {
+                    break;
+                }
+            }
+            message = upstream_receiver.next() => {
+                let message = match message {
+                    Some(Ok(tungstenite::Message::Text(text))) => WsMessage::Text(text),
+                    Some(Ok(tungstenite::Message::Binary(bytes))) => WsMessage::Binary(bytes),
+                    Some(Ok(tungstenite::Message::Close(_))) | Some(Err(_)) | None => {
+                        steps.push(WsStep::Close);
+                        let _ = sender.send(Message::close()).await;
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                let now = Instant::now();
+                steps.push(WsStep::send_after(
+                    now.duration_since(last_step).as_millis() as u64,
+                    message.clone(),
+                ));
+                last_step = now;
+                if sender.send(to_warp(message)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    proxy.recorder.record(Replay {
+        when: proxy.request,
+        then: DynamicBody::WebSocket(steps),
+        version: None,
+    });
+}
@@ -0,0 +1,58 @@
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, KeyUsagePurpose,
+};
+
+/// The certificates used to serve https. We generate a CA, and a leaf certificate
+/// signed by it, so a test client only has to trust the CA.
+#[derive(Debug, Clone)]
+pub struct TlsCertificates {
+    /// The CA certificate as PEM, add this to the client's trusted roots
+    pub ca_pem: String,
+    /// The leaf certificate the server presents, as PEM
+    pub cert_pem: String,
+    /// The private key of the leaf certificate, as PEM
+    pub key_pem: String,
+}
+
+impl TlsCertificates {
+    /// Generate a new CA and a leaf certificate for `localhost`, `127.0.0.1`, `::1` and `0.0.0.0`
+    pub fn generate() -> Self {
+        Self::generate_for(&["localhost", "127.0.0.1", "::1", "0.0.0.0"])
+    }
+
+    /// Generate a new CA and a leaf certificate for the given host names and ips
+    pub fn generate_for(names: &[&str]) -> Self {
+        let ca_key = KeyPair::generate().expect("generating CA key");
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).expect("CA params");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        ca_params.distinguished_name = {
+            let mut name = DistinguishedName::new();
+            name.push(DnType::CommonName, "replay-mocker CA");
+            name
+        };
+        let ca_cert = ca_params.self_signed(&ca_key).expect("signing CA");
+
+        let key = KeyPair::generate().expect("generating leaf key");
+        let mut params =
+            CertificateParams::new(names.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+                .expect("leaf params");
+        params.distinguished_name = {
+            let mut name = DistinguishedName::new();
+            name.push(
+                DnType::CommonName,
+                names.first().copied().unwrap_or("localhost"),
+            );
+            name
+        };
+        let cert = params
+            .signed_by(&key, &ca_cert, &ca_key)
+            .expect("signing leaf certificate");
+
+        Self {
+            ca_pem: ca_cert.pem(),
+            cert_pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+        }
+    }
+}